@@ -0,0 +1,65 @@
+// 比较逐块扫描FAT和使用空闲块位图分配空闲块的耗时
+//
+// 运行：cargo bench --bench allocation
+
+use std::env;
+use std::fs;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rust_file_system::{FatStatus, FileDevice, Geometry, VirtualDisk};
+
+const BLOCK_SIZE: usize = 512;
+// 每次分配再释放的块数
+const BLOCKS_PER_FILE: usize = 256;
+
+// 在稀疏的镜像文件上创建虚拟磁盘，前一半的块已被占用
+fn half_full_disk(block_count: usize) -> VirtualDisk<FileDevice> {
+    let path = env::temp_dir().join(format!("rsfs_bench_{}_{}", std::process::id(), block_count));
+    let geometry: Geometry = Geometry::new(BLOCK_SIZE, block_count).unwrap();
+    let device: FileDevice = FileDevice::create(path.to_str().unwrap(), BLOCK_SIZE, geometry.device_block_count()).unwrap();
+    // 镜像文件只在基准测试中使用，打开后就可以删除
+    fs::remove_file(&path).unwrap();
+    let mut disk: VirtualDisk<FileDevice> = VirtualDisk::format(device, geometry).unwrap();
+    for block in 0..block_count / 2 {
+        disk.set_fat(block, FatStatus::EOF);
+    }
+    disk
+}
+
+// 原来的做法：每分配一块都从头扫描FAT
+fn find_by_scanning(disk: &VirtualDisk<FileDevice>) -> Option<usize> {
+    disk.fat().iter().position(|fat_item| matches!(fat_item, FatStatus::UnUsed))
+}
+
+// 分配一条块链然后释放
+fn allocate_and_free(disk: &mut VirtualDisk<FileDevice>, find: fn(&VirtualDisk<FileDevice>) -> Option<usize>) {
+    let mut blocks: Vec<usize> = Vec::with_capacity(BLOCKS_PER_FILE);
+    for i in 0..BLOCKS_PER_FILE {
+        let block: usize = find(disk).unwrap();
+        if i != 0 {
+            disk.set_fat(blocks[i - 1], FatStatus::NextBlock(block));
+        }
+        disk.set_fat(block, FatStatus::EOF);
+        blocks.push(block);
+    }
+    for block in blocks {
+        disk.set_fat(block, FatStatus::UnUsed);
+    }
+}
+
+fn allocation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("allocate_256_blocks");
+    group.sample_size(10);
+    for block_count in [16 * 1024, 128 * 1024, 1024 * 1024] {
+        let mut disk: VirtualDisk<FileDevice> = half_full_disk(block_count);
+        group.bench_function(BenchmarkId::new("fat_scan", block_count), |b| {
+            b.iter(|| allocate_and_free(&mut disk, find_by_scanning))
+        });
+        group.bench_function(BenchmarkId::new("free_bitmap", block_count), |b| {
+            b.iter(|| allocate_and_free(&mut disk, VirtualDisk::first_free_block))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, allocation);
+criterion_main!(benches);
@@ -1,324 +1,666 @@
+pub mod allocation;
+pub mod block_device;
+pub mod block_map;
+pub mod defrag;
+pub mod error;
+pub mod file_handle;
+pub mod free_space;
+pub mod fsck;
+pub mod image;
+pub mod journal;
+pub mod scrub;
 pub mod virtual_disk;
+pub mod virtual_file;
+#[cfg(test)]
+mod tests;
 
 use std::str;
-use core::panic;
-use ansi_rgb::Foreground;
+use std::fs;
+use std::io::SeekFrom;
+use log::{debug, info};
 use serde::{Deserialize, Serialize};
-use std::{fmt, vec::Vec, string::String, usize};
-use virtual_disk::{FatStatus, VirtualDisk, BLOCK_SIZE, BLOCK_COUNT};
+use std::{fmt, vec::Vec, string::String};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use allocation::{AllocationPolicy, FragmentationStats};
+use block_device::{BlockDevice, FileDevice, MemoryDevice};
+use block_map::{BlockMap, BlockState, FileFragments};
+use defrag::{DefragReport, TreeNode};
+use error::{FsError, FsResult};
+use file_handle::{FileHandle, OpenFile, OpenFlags};
+use fsck::{FsckFinding, FsckProblem, FsckReport};
+use image::Geometry;
+use scrub::{BadBlock, ScrubReport};
+use virtual_disk::{FatStatus, VirtualDisk};
+
+
+// 根目录所在的块
+pub const ROOT_BLOCK: usize = 0;
+
+// fsck修复时最多重复检查的次数
+const FSCK_MAX_PASSES: usize = 8;
+
+/// 文件系统：虚拟磁盘和当前所在目录，D是保存虚拟磁盘的块设备
+pub struct DiskInfo<D: BlockDevice = MemoryDevice> {
+    virtual_disk: VirtualDisk<D>,
+    cur_directory: Directory,    // 当前目录，修改可能还没有写入磁盘
+    cur_path: String,    // 当前目录的绝对路径
+    policy: AllocationPolicy,    // 分配空闲块的策略
+    next_fit_cursor: usize,    // 上次分配结束的位置，循环首次适应从这里开始查找
+    open_files: HashMap<usize, OpenFile>,    // 打开的文件，键为句柄编号
+    next_handle: usize,    // 下一个句柄的编号
+}
+
+
+impl DiskInfo<MemoryDevice> {
+    /// 创建新文件系统，返回DiskInfo对象,root_dir为空表示按默认设置创建
+    pub fn new(root_dir: Option<Directory>) -> DiskInfo<MemoryDevice> {
+        info!("Creating new file system");
+        // 数据区在内存中，写入不会失败
+        DiskInfo::format_virtual_disk(VirtualDisk::new(), root_dir).unwrap()
+    }
 
+    /// 按给定的磁盘参数创建新文件系统，数据全部放在内存中
+    pub fn format(geometry: Geometry) -> FsResult<DiskInfo<MemoryDevice>> {
+        info!("Creating new file system with {} blocks of {} bytes", geometry.block_count, geometry.block_size);
+        DiskInfo::format_virtual_disk(VirtualDisk::with_geometry(geometry)?, None)
+    }
+
+    /// 从镜像文件加载文件系统，整个镜像都读入内存
+    pub fn load_from_file(path: &str) -> FsResult<DiskInfo<MemoryDevice>> {
+        info!("Loading file system from '{}'...", path);
+        let data: Vec<u8> = fs::read(path)?;
+        DiskInfo::from_image(&data)
+    }
 
-#[derive(Serialize, Deserialize)]
-pub struct DiskInfo {
-    pub virtual_disk: VirtualDisk,
-    pub cur_directory: Directory,
+    /// 从镜像数据打开文件系统，总是从根目录开始
+    pub fn from_image(image: &[u8]) -> FsResult<DiskInfo<MemoryDevice>> {
+        DiskInfo::with_virtual_disk(VirtualDisk::from_image(image)?)
+    }
 }
 
+impl DiskInfo<FileDevice> {
+    /// 按给定的磁盘参数在path创建新的镜像文件并在其中创建文件系统，之后的修改直接写入镜像文件
+    pub fn create(path: &str, geometry: Geometry) -> FsResult<DiskInfo<FileDevice>> {
+        info!("Creating new file system at '{}'...", path);
+        // 先检查磁盘参数，避免参数错误时清空已有的文件
+        let geometry: Geometry =
+            Geometry::new(geometry.block_size, geometry.block_count)?.with_journal_blocks(geometry.journal_blocks)?;
+        let device: FileDevice = FileDevice::create(path, geometry.block_size, geometry.device_block_count())?;
+        DiskInfo::format_device(device, geometry)
+    }
+
+    /// 打开镜像文件，数据块在需要时才从文件中读取，修改直接写入镜像文件
+    pub fn open(path: &str) -> FsResult<DiskInfo<FileDevice>> {
+        info!("Opening file system at '{}'...", path);
+        // 块大小以镜像超级块中记录的为准
+        let geometry: Geometry = Geometry::read_from_image(path)?;
+        DiskInfo::open_device(FileDevice::open(path, geometry.block_size)?)
+    }
+}
 
-impl DiskInfo {
-    // 创建新文件系统，返回DiskInfo对象,root_dir为空表示按默认设置创建
-    pub fn new(root_dir: Option<Directory>) -> DiskInfo {
-        print_info();
-        println!("Creating new file system");
-        // 创建VirtualDisk
-        let mut disk = VirtualDisk::new();
+impl<D: BlockDevice> DiskInfo<D> {
+    /// 按给定的磁盘参数在块设备上创建新文件系统，块设备上原有的数据会被覆盖
+    pub fn format_device(device: D, geometry: Geometry) -> FsResult<DiskInfo<D>> {
+        DiskInfo::format_virtual_disk(VirtualDisk::format(device, geometry)?, None)
+    }
+
+    /// 打开块设备上已有的文件系统，从根目录开始
+    pub fn open_device(device: D) -> FsResult<DiskInfo<D>> {
+        DiskInfo::with_virtual_disk(VirtualDisk::open(device)?)
+    }
+
+    // 在空的虚拟磁盘上写入根目录
+    fn format_virtual_disk(mut disk: VirtualDisk<D>, root_dir: Option<Directory>) -> FsResult<DiskInfo<D>> {
+        // 默认根目录配置，根目录的".."和"."都指向根目录自己
+        let mut root: Directory = Directory {
+            name: String::from("root"),
+            files: vec![
+                Fcb {
+                    name: String::from(".."),
+                    file_type: FileType::Directory,
+                    first_block: ROOT_BLOCK,
+                    length: 0,
+                },
+                Fcb {
+                    name: String::from("."),
+                    file_type: FileType::Directory,
+                    first_block: ROOT_BLOCK,
+                    length: 0,
+                },
+            ],
+        };
+        if let Some(dir) = root_dir {
+            root.name = dir.name;
+            root.files.extend(dir.files.into_iter().filter(|fcb| fcb.name != ".." && fcb.name != "."));
+        }
+        let root_dir: Directory = root;
         {
-            // 创建根目录
+            // 根目录写入固定的块，这样通过绝对路径总能找到根目录
             let dir_data: Vec<u8> = bincode::serialize(&root_dir).unwrap();
-            disk.insert_data_by_offset(dir_data.as_slice(), 0);
+            disk.write_data_by_blocks(dir_data.as_slice(), &[ROOT_BLOCK])?;
         }
-        disk.fat[0] = FatStatus::EOF;
+        disk.set_fat(ROOT_BLOCK, FatStatus::EOF);
+        disk.flush()?;
 
-        DiskInfo {
+        Ok(DiskInfo {
             virtual_disk: disk,
-            cur_directory: match root_dir {
-                // 默认根目录配置
-                None => Directory {
-                    name: String::from("root"),
-                    files: vec![
-                        Fcb {
-                            name: String::from(".."),
-                            file_type: FileType::Directory,
-                            first_block: 0,
-                            length: 0,
-                        },
-                        Fcb {
-                            name: String::from("."),
-                            file_type: FileType::Directory,
-                            first_block: 0,
-                            length: 0,
-                        },
-                    ],
-                },
-                Some(dir) => dir,
-            },
+            cur_directory: root_dir,
+            cur_path: String::from("/"),
+            policy: AllocationPolicy::default(),
+            next_fit_cursor: 0,
+            open_files: HashMap::new(),
+            next_handle: 0,
+        })
+    }
+
+    // 在已有的虚拟磁盘上打开文件系统，从根目录开始
+    fn with_virtual_disk(virtual_disk: VirtualDisk<D>) -> FsResult<DiskInfo<D>> {
+        let mut disk: DiskInfo<D> = DiskInfo {
+            virtual_disk,
+            cur_directory: Directory::new(""),
+            cur_path: String::from("/"),
+            policy: AllocationPolicy::default(),
+            next_fit_cursor: 0,
+            open_files: HashMap::new(),
+            next_handle: 0,
+        };
+        disk.cur_directory = disk.read_directory_from_disk(ROOT_BLOCK)?;
+        Ok(disk)
+    }
+
+    /// 将整个文件系统保存到镜像文件，当前目录的修改会先写入磁盘
+    /// 先写入临时文件再改名覆盖原来的镜像，保存失败时原来的镜像不受影响
+    pub fn save_to_file(&mut self, path: &str) -> FsResult<()> {
+        self.save_to_file_with_backups(path, 0)
+    }
+
+    /// 和save_to_file相同，并把原来的镜像保留为path.bak1、path.bak2……，最多保留generations代
+    pub fn save_to_file_with_backups(&mut self, path: &str, generations: usize) -> FsResult<()> {
+        info!("Saving file system to '{}'...", path);
+        let data: Vec<u8> = self.to_image()?;
+        image::write_image_file(path, data.as_slice(), generations)
+    }
+
+    /// 生成镜像数据，当前目录的修改会先写入磁盘
+    pub fn to_image(&mut self) -> FsResult<Vec<u8>> {
+        self.flush()?;
+        self.virtual_disk.to_image()
+    }
+
+    // 在事务中执行op：op成功时提交，op或提交失败时撤销对FAT和磁盘块的所有修改，并恢复当前目录和打开的文件
+    // 已经在事务中时直接执行，由外层的事务负责提交或撤销
+    fn transaction<T, F: FnOnce(&mut DiskInfo<D>) -> FsResult<T>>(&mut self, op: F) -> FsResult<T> {
+        if !self.virtual_disk.begin_transaction() {
+            return op(self);
         }
+        let cur_directory: Directory = self.cur_directory.clone();
+        let cur_path: String = self.cur_path.clone();
+        let next_fit_cursor: usize = self.next_fit_cursor;
+        let open_files: HashMap<usize, OpenFile> = self.open_files.clone();
+        let result: FsResult<T> = op(self).and_then(|value| self.virtual_disk.commit_transaction().map(|()| value));
+        if result.is_err() {
+            self.virtual_disk.rollback_transaction();
+            self.cur_directory = cur_directory;
+            self.cur_path = cur_path;
+            self.next_fit_cursor = next_fit_cursor;
+            self.open_files = open_files;
+        }
+        result
+    }
+
+    /// 当前目录
+    pub fn current_directory(&self) -> &Directory {
+        &self.cur_directory
+    }
+
+    /// 当前目录的绝对路径，如"/src/bin"
+    pub fn current_path(&self) -> &str {
+        &self.cur_path
+    }
+
+    /// 虚拟磁盘，只能读取，修改都要通过DiskInfo的方法进行
+    pub fn virtual_disk(&self) -> &VirtualDisk<D> {
+        &self.virtual_disk
+    }
+
+    /// 当前的分配策略
+    pub fn allocation_policy(&self) -> AllocationPolicy {
+        self.policy
+    }
+
+    /// 修改分配策略，只影响之后的分配
+    pub fn set_allocation_policy(&mut self, policy: AllocationPolicy) {
+        self.policy = policy;
+    }
+
+    /// 统计当前磁盘的碎片情况
+    pub fn fragmentation_stats(&self) -> FragmentationStats {
+        FragmentationStats::from_fat(self.policy, self.virtual_disk.fat(), self.virtual_disk.free_space())
     }
 
-    // 遍历查找第一个空闲块的块号
-    // TODO 有优化的空间
+    /// 查找第一个空闲块的块号，通过空闲块位图查找，不需要扫描整个FAT
     pub fn find_next_empty_fat(&self) -> Option<usize> {
-        let mut res: Option<usize> = None;
-        for i in 0..(self.virtual_disk.fat.len() - 1) {
-            if let FatStatus::UnUsed = self.virtual_disk.fat[i] {
-                res = Some(i);
-                break;
-            }
-        }
-        res
+        self.virtual_disk.first_free_block()
     }
 
-    // 查询是否有指定数量的空闲块，如果有在FAT表中修改相关值，然后返回块号数组
-    pub fn allocate_free_space_on_fat(
-        &mut self,
-        blocks_needed: usize,
-    ) -> Result<Vec<usize>, &'static str> {
-        print_info();
-        println!("Allocating new space...");
-
-        let mut blocks: Vec<usize> = Vec::with_capacity(blocks_needed);
-        for i in 0..blocks_needed {
-            // 找到一个空闲块
-            blocks.push(match self.find_next_empty_fat() {
-                Some(block) => block,
-                _ => return Err("[ERROR]\tCannot find a NotUsed FatItem!"),
-            });
-            
-            let cur_block: usize = blocks[i];
+    /// 查询是否有指定数量的空闲块，如果有在FAT表中修改相关值，然后返回块号数组
+    /// 空闲块不够时返回NoSpace，FAT保持不变
+    pub fn allocate_free_space_on_fat(&mut self, blocks_needed: usize) -> FsResult<Vec<usize>> {
+        info!("Allocating new space...");
+
+        // 先检查空闲块是否足够，避免分配到一半才发现空间不足
+        if blocks_needed > self.virtual_disk.free_block_count() {
+            return Err(FsError::NoSpace);
+        }
+
+        // 按分配策略选出所有空闲块之后再修改FAT，选块失败时FAT不变
+        let blocks: Vec<usize> = self
+            .policy
+            .choose_blocks(self.virtual_disk.free_space(), self.next_fit_cursor, blocks_needed)
+            .ok_or(FsError::NoSpace)?;
+        debug!("Found new empty blocks ({}): {:?}", self.policy, blocks);
 
-            // 对磁盘写入数据
-            print_debug_info();
-            println!("Found new empty block: {}", cur_block);
+        for i in 0..blocks.len() {
             if i != 0 {
                 // 从第二块开始，将上一块的FAT值修改为当前块
-                self.virtual_disk.fat[blocks[i - 1]] = FatStatus::NextBlock(cur_block);
+                self.virtual_disk.set_fat(blocks[i - 1], FatStatus::NextBlock(blocks[i]));
             }
-            // 每次都将当前块作为最后一块，防止出现没有空闲块提前退出的情况
-            self.virtual_disk.fat[cur_block] = FatStatus::EOF;
+            self.virtual_disk.set_fat(blocks[i], FatStatus::EOF);
+        }
+        if let Some(last) = blocks.last() {
+            self.next_fit_cursor = last + 1;
         }
 
         Ok(blocks)
     }
 
+    // 释放已经分配的块，用于操作失败时撤销分配
+    fn release_blocks(&mut self, blocks: &[usize]) {
+        for block in blocks {
+            self.virtual_disk.set_fat(*block, FatStatus::UnUsed);
+        }
+    }
+
     // 获取以first_block为开头在FAT中所关联的所有文件块
-    fn get_file_blocks(&self, first_block: usize) -> Result<Vec<usize>, String> {
-        print_info();
-        println!("Searching file blocks...");
+    fn get_file_blocks(&self, first_block: usize) -> FsResult<Vec<usize>> {
+        info!("Searching file blocks...");
+        let fat: &[FatStatus] = self.virtual_disk.fat();
         let mut blocks: Vec<usize> = Vec::new();
         let mut cur_block: usize = first_block;
 
         // 第一块
         blocks.push(first_block);
 
-        // 循环读出之后所有块
+        // 循环读出之后所有块，块号超出范围或者链中有环时返回错误
         loop {
-            match self.virtual_disk.fat[cur_block] {
+            if cur_block >= fat.len() || blocks.len() > fat.len() {
+                break Err(FsError::CorruptChain(cur_block));
+            }
+            match fat[cur_block] {
                 FatStatus::NextBlock(block) => {
-                    print_debug_info();
-                    println!("Found next block: {}.", block);
+                    debug!("Found next block: {}.", block);
                     blocks.push(block);
                     cur_block = block;
                 }
                 FatStatus::EOF => {
-                    print_debug_info();
-                    println!("Found EoF block: {}.", cur_block);
+                    debug!("Found EoF block: {}.", cur_block);
                     break Ok(blocks);
                 }
-                FatStatus::UnUsed => {
-                    break Err(format!(
-                        "[ERROR]\tBad block detected at {}!",
-                        cur_block
-                    ))
-                }
+                FatStatus::UnUsed => break Err(FsError::CorruptChain(cur_block)),
             }
         }
     }
 
     // 释放从first_block开始已经被分配的块
-    fn delete_space_on_fat(&mut self, first_block: usize) -> Result<Vec<usize>, String> {
-        print_info();
-        println!("Deleting Fat space...");
-        let blocks_result: Result<Vec<usize>, String> = self.get_file_blocks(first_block);
-        let blocks: Vec<usize> = blocks_result.clone().unwrap();
-        for block in blocks {
-            self.virtual_disk.fat[block] = FatStatus::UnUsed;
+    fn delete_space_on_fat(&mut self, first_block: usize) -> FsResult<Vec<usize>> {
+        info!("Deleting Fat space...");
+        // 先完整取出整条链，链损坏时不修改FAT
+        let blocks: Vec<usize> = self.get_file_blocks(first_block)?;
+        for block in &blocks {
+            self.virtual_disk.set_fat(*block, FatStatus::UnUsed);
         }
 
-        blocks_result
+        Ok(blocks)
     }
 
-    // 计算写入文件需要的块数量——针对EoF
-    // 返回（`bool`: 是否需要插入EoF，`usize`: 需要的总块数）
-    fn calc_blocks_needed_with_eof(length: usize) -> (bool, usize) {
-        // 需要的块数
-        let mut blocks_needed: f32 = length as f32 / BLOCK_SIZE as f32;
-
-        // 需要的块数为整数不需要写入结束标志，否则需要写入结束标志
-        let insert_eof: bool = if (blocks_needed - blocks_needed as usize as f32) < 0.0000000001 {
-            false
-        } else {
-            // 向上取整
-            blocks_needed = blocks_needed.ceil();
-            true
-        };
-        let blocks_needed: usize = blocks_needed as usize;
-
-        (insert_eof, blocks_needed)
+    // 计算写入length字节的数据需要的块数量
+    fn calc_blocks_needed(&self, length: usize) -> usize {
+        // 空文件也占用一个块，这样FCB中总有首块块号
+        length.div_ceil(self.virtual_disk.block_size()).max(1)
     }
 
-    // 写入的数据到硬盘，返回first_block
-    pub fn write_data_to_disk(&mut self, data: &[u8]) -> usize {
-        print_info();
-        println!("Writing data to disk...");
+    /// 写入的数据到硬盘，返回first_block
+    pub fn write_data_to_disk(&mut self, data: &[u8]) -> FsResult<usize> {
+        info!("Writing data to disk...");
+
+        let blocks_needed: usize = self.calc_blocks_needed(data.len());
 
-        let (insert_eof, blocks_needed) = DiskInfo::calc_blocks_needed_with_eof(data.len());
+        let blocks: Vec<usize> = self.allocate_free_space_on_fat(blocks_needed)?;
 
-        let blocks: Vec<usize> = self.allocate_free_space_on_fat(blocks_needed).unwrap();
+        if let Err(err) = self.virtual_disk.write_data_by_blocks(data, blocks.as_slice()) {
+            // 写入失败时撤销分配
+            self.release_blocks(&blocks);
+            return Err(err);
+        }
 
-        self.virtual_disk.write_data_by_blocks_with_eof(data, blocks.as_slice(), insert_eof);
+        debug!("Writing finished. Returned blocks: {:?}", blocks);
 
-        print_debug_info();
-        println!("Writing finished. Returned blocks: {:?}", blocks);
+        Ok(blocks[0])
+    }
 
-        blocks[0]
+    /// 新建目录并且写入磁盘，path可以是绝对路径或相对路径
+    pub fn new_directory_to_disk(&mut self, path: &str) -> FsResult<()> {
+        self.transaction(|disk| disk.new_directory_to_disk_in_transaction(path))
     }
 
-    // 在当前目录中新建目录，并且写入磁盘
-    pub fn new_directory_to_disk(&mut self, name: &str) -> Result<(), &'static str> {
+    // new_directory_to_disk的实现，在事务中执行
+    fn new_directory_to_disk_in_transaction(&mut self, path: &str) -> FsResult<()> {
+        let (mut parent, name) = self.resolve_parent(path)?;
         // 新文件夹写入磁盘块
-        print_info();
-        println!("Creating dir: {}.", name);
-        print_debug_info();
-        println!("Trying to write to disk...");
+        info!("Creating dir: {}.", name);
+        debug!("Trying to write to disk...");
+
+        Directory::check_name(&name)?;
+        if parent.get_fcb_by_name(&name).is_some() {
+            return Err(FsError::AlreadyExists(name));
+        }
+
+        // 将新建的目录写入到硬盘
+        let first_block: usize = self.write_empty_directory_to_disk(&name, parent.files[1].first_block)?;
 
-        if let Some(_fcb) = self.cur_directory.get_fcb_by_name(name) {
-            return Err("[ERROR]\tThere's already a directory with a same name!");
+        debug!("Trying to add dir to parent dir...");
+
+        // 在父目录添加新目录
+        parent.files.push(Fcb {
+            name: name.clone(),
+            file_type: FileType::Directory,
+            first_block,
+            length: 0,
+        });
+        if let Err(err) = self.store_directory(&parent) {
+            // 父目录放不下新的目录项时释放新目录占用的块
+            self.delete_space_on_fat(first_block)?;
+            return Err(err);
         }
+        debug!("Created dir {}.", name);
+
+        // 新目录和父目录在同一个事务中写入，中途断电时两者都不会写入
+        Ok(())
+    }
 
+    // 将只有".."和"."两项的新目录写入磁盘，返回新目录的首块块号
+    fn write_empty_directory_to_disk(&mut self, name: &str, parent_block: usize) -> FsResult<usize> {
         // Directory对象是目录的数据，每个数据项是一个Fcb
         let mut new_directory: Directory = Directory::new(name);
         // 添加父目录，用于cd切换到父目录
         new_directory.files.push(Fcb {
             name: String::from(".."),
             file_type: FileType::Directory,
-            first_block: self.cur_directory.files[1].first_block,
+            first_block: parent_block,
             length: 0,
         });
-        // TODO: 为什么要加入自己？
+        // "."记录目录自己的首块，保存目录时通过它找到目录所在的块
+        // 首块由分配策略决定，先写入占位的块号，分配后再改为真正的首块
         new_directory.files.push(Fcb {
             name: String::from("."),
             file_type: FileType::Directory,
-            first_block: self.find_next_empty_fat().unwrap(),
+            first_block: 0,
             length: 0,
         });
 
         let bin_dir: Vec<u8> = bincode::serialize(&new_directory).unwrap();
+        let first_block: usize = self.write_data_to_disk(&bin_dir)?;
 
-        print_debug_info();
-        println!("Dir bytes: {:?}", bin_dir);
-        // 将新建的目录写入到硬盘
-        let first_block: usize = self.write_data_to_disk(&bin_dir);
-
-        print_debug_info();
-        println!("Trying to add dir to current dir...");
-
-        // 在当前目录添加新目录
-        self.cur_directory.files.push(Fcb {
-            name: String::from(name),
-            file_type: FileType::Directory,
-            first_block,
-            length: 0,
-        });
-        print_debug_info();
-        println!("Created dir {}.", name);
-
-        // 这里并没有立即更新当前目录到硬盘，而是等切换目录或退出时再保存
-        // 因为可能创建多个目录，如果每创建一个就更新一次效率会比较低
-        // 但也会有新的问题，比如没有正常退出（如断电）会导致数据丢失
-        Ok(())
+        // 块号定长存储，数据长度不变，直接覆写原来的块
+        new_directory.files[1].first_block = first_block;
+        let bin_dir: Vec<u8> = bincode::serialize(&new_directory).unwrap();
+        debug!("Dir bytes: {}", bin_dir.len());
+        self.overwrite_data_on_disk(first_block, &bin_dir)
     }
 
-    // 根据首块块号，读出所有数据
-    fn get_data_by_first_block(&self, first_block: usize) -> Vec<u8> {
-        print_debug_info();
-        println!("Getting data from disk by blocks...");
+    // 根据首块块号，读出块链中所有块的完整数据
+    fn get_data_by_first_block(&self, first_block: usize) -> FsResult<Vec<u8>> {
+        debug!("Getting data from disk by blocks...");
 
-        let blocks: Vec<usize> = self.get_file_blocks(first_block).unwrap();
+        let blocks: Vec<usize> = self.get_file_blocks(first_block)?;
         let data: Vec<u8> = self
             .virtual_disk
-            .read_data_by_blocks_without_eof(blocks.as_slice());
+            .read_data_by_blocks(blocks.as_slice())?;
 
-        print_debug_info();
-        println!("Data read: {:?}", &data);
+        debug!("Data read: {} bytes", data.len());
 
-        data
+        Ok(data)
     }
 
     // 通过FCB块找到目录数据
-    fn get_directory_by_fcb(&self, dir_fcb: &Fcb) -> Directory {
-        print_info();
-        println!("Getting dir by FCB...\n\tFCB: {:?}", dir_fcb);
+    fn get_directory_by_fcb(&self, dir_fcb: &Fcb) -> FsResult<Directory> {
+        info!("Getting dir by FCB...\n\tFCB: {:?}", dir_fcb);
         match dir_fcb.file_type {
-            FileType::Directory => {
-                let data_dir = self.get_data_by_first_block(dir_fcb.first_block);
-                print_debug_info();
-                println!("Trying to deserialize data read from disk...");
-                let dir: Directory = bincode::deserialize(data_dir.as_slice()).unwrap();
-                print_debug_info();
-                println!("Getting dir finished.");
-                dir
-            }
-            _ => panic!("[ERROR]\tGet Directory recieved a non-Directory FCB!"),
+            FileType::Directory => self.load_directory(dir_fcb.first_block).map_err(|err| err.in_file(&dir_fcb.name)),
+            _ => Err(FsError::NotADirectory(dir_fcb.name.clone())),
+        }
+    }
+
+    // 通过首块块号读取目录。当前目录以内存中的为准，因为它的修改可能还没有写入磁盘
+    fn load_directory(&self, first_block: usize) -> FsResult<Directory> {
+        if first_block == self.cur_directory.files[1].first_block {
+            return Ok(self.cur_directory.clone());
+        }
+        self.read_directory_from_disk(first_block)
+    }
+
+    // 通过首块块号从磁盘读取目录
+    fn read_directory_from_disk(&self, first_block: usize) -> FsResult<Directory> {
+        let data_dir = self.get_data_by_first_block(first_block)?;
+        debug!("Trying to deserialize data read from disk...");
+        let dir: Directory = bincode::deserialize(data_dir.as_slice())
+            .map_err(|_| FsError::CorruptChain(first_block))?;
+        debug!("Getting dir finished.");
+        Ok(dir)
+    }
+
+    // 保存修改后的目录，写回原来的块。修改的是当前目录时同时更新内存中的当前目录
+    fn store_directory(&mut self, dir: &Directory) -> FsResult<()> {
+        self.save_directory_to_disk(dir)?;
+        if dir.files[1].first_block == self.cur_directory.files[1].first_block {
+            self.cur_directory = dir.clone();
         }
+        Ok(())
     }
 
     // 通过FCB块找到文件数据
-    fn get_file_by_fcb(&self, fcb: &Fcb) -> Vec<u8> {
-        print_info();
-        println!("Getting file data by FCB...\n\tFCB: {:?}", fcb);
+    fn get_file_by_fcb(&self, fcb: &Fcb) -> FsResult<Vec<u8>> {
+        info!("Getting file data by FCB...\n\tFCB: {:?}", fcb);
         match fcb.file_type {
-            FileType::File => self.get_data_by_first_block(fcb.first_block),
-            _ => panic!("[ERROR]\tGet File recieved a non-File FCB!"),
+            FileType::File => self.get_file_data(fcb.first_block, fcb.length).map_err(|err| err.in_file(&fcb.name)),
+            _ => Err(FsError::IsADirectory(fcb.name.clone())),
         }
     }
 
+    // 读出文件的数据，最后一块中数据之后的部分是填充，按FCB中记录的长度截断
+    fn get_file_data(&self, first_block: usize, length: usize) -> FsResult<Vec<u8>> {
+        let mut data: Vec<u8> = self.get_data_by_first_block(first_block)?;
+        if data.len() < length {
+            // 块链比记录的长度短
+            return Err(FsError::CorruptChain(first_block));
+        }
+        data.truncate(length);
+        Ok(data)
+    }
 
-    // 在当前目录新建文件并写入数据
-    pub fn create_file_with_data(&mut self, name: &str, data: &[u8]) {
-        print_info();
-        println!("Creating new file in current dir...");
+    /// 解析路径，返回路径指向的目录
+    /// 支持绝对路径（"/a/b"）、相对路径（"a/b"、"./a"、"../a"），连续的'/'视为一个
+    pub fn get_directory_by_path(&self, path: &str) -> FsResult<Directory> {
+        let mut dir: Directory = if path.starts_with('/') {
+            self.load_directory(ROOT_BLOCK)?
+        } else {
+            self.cur_directory.clone()
+        };
+
+        for name in path.split('/') {
+            if name.is_empty() || name == "." {
+                continue;
+            }
+            let (_, fcb) = dir
+                .get_fcb_by_name(name)
+                .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+            if let FileType::File = fcb.file_type {
+                return Err(FsError::NotADirectory(String::from(path)));
+            }
+            dir = self.load_directory(fcb.first_block)?;
+        }
+
+        Ok(dir)
+    }
+
+    // 解析路径，返回最后一级所在的目录和最后一级的名字
+    // 路径以'/'结尾或者只有目录部分时（如"/"、".."），名字为"."或".."，由调用者决定是否合法
+    fn resolve_parent(&self, path: &str) -> FsResult<(Directory, String)> {
+        let path: &str = match path.trim_end_matches('/') {
+            "" if path.starts_with('/') => "/.",
+            trimmed => trimmed,
+        };
+        let (dir_path, name) = match path.rfind('/') {
+            Some(index) => (&path[..=index], &path[index + 1..]),
+            None => ("", path),
+        };
+        if name.is_empty() {
+            return Err(FsError::InvalidName(String::from(path)));
+        }
+        let dir: Directory = self.get_directory_by_path(dir_path)?;
+
+        Ok((dir, String::from(name)))
+    }
+
+    // 解析路径，返回最后一级所在的目录和对应的FCB
+    fn resolve_fcb(&self, path: &str) -> FsResult<(Directory, Fcb)> {
+        let (dir, name) = self.resolve_parent(path)?;
+        let (_, fcb) = dir
+            .get_fcb_by_name(&name)
+            .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+        let fcb: Fcb = fcb.clone();
+
+        Ok((dir, fcb))
+    }
+
+    /// 新建文件并写入数据，path可以是绝对路径或相对路径
+    pub fn create_file_with_data(&mut self, path: &str, data: &[u8]) -> FsResult<()> {
+        self.transaction(|disk| disk.create_file_with_data_in_transaction(path, data))
+    }
+
+    // create_file_with_data的实现，在事务中执行
+    fn create_file_with_data_in_transaction(&mut self, path: &str, data: &[u8]) -> FsResult<()> {
+        info!("Creating new file '{}'...", path);
+        let (mut parent, name) = self.resolve_parent(path)?;
+        Directory::check_name(&name)?;
+        if parent.get_fcb_by_name(&name).is_some() {
+            return Err(FsError::AlreadyExists(name));
+        }
         // 写入数据
-        let first_block = self.write_data_to_disk(data);
-        // 创建新FCB并插入当前目录中
+        let first_block = self.write_data_to_disk(data)?;
+        // 创建新FCB并插入父目录中
         let fcb: Fcb = Fcb {
-            name: String::from(name),
+            name,
             file_type: FileType::File,
-            first_block: first_block,
+            first_block,
             length: data.len(),
         };
-        self.cur_directory.files.push(fcb);
+        parent.files.push(fcb);
+        if let Err(err) = self.store_directory(&parent) {
+            // 父目录放不下新的目录项时释放文件数据占用的块
+            self.delete_space_on_fat(first_block)?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// 通过路径读取文件
+    pub fn read_file_by_name(&self, path: &str) -> FsResult<Vec<u8>> {
+        let (_dir, fcb) = self.resolve_fcb(path)?;
+        self.get_file_by_fcb(&fcb)
+    }
+
+    /// 通过路径找到文件或目录的FCB
+    pub fn get_fcb_by_path(&self, path: &str) -> FsResult<Fcb> {
+        self.resolve_fcb(path).map(|(_dir, fcb)| fcb)
     }
 
-    // 通过文件名读取文件
-    pub fn read_file_by_name(&self, name: &str) -> Vec<u8> {
-        let (_index, fcb) = self.cur_directory.get_fcb_by_name(name).unwrap();
-        self.get_file_by_fcb(fcb)
+    /// 通过路径删除文件或空目录
+    pub fn delete_file_by_name(&mut self, path: &str) -> FsResult<()> {
+        self.transaction(|disk| disk.delete_file_by_name_in_transaction(path))
     }
 
-    // 通过文件名删除文件
-    pub fn delete_file_by_name(&mut self, name: &str) -> Result<(), String> {
-        let index: usize = self.cur_directory.get_index_by_name(name).unwrap();
-        // 从dir中先删除fcb，如果删除失败再还回来
-        print_debug_info();
-        println!("Trying to delete file in dir file list...");
-        let fcb: Fcb = self.cur_directory.files.remove(index);
-        let res: Result<(), String> = self.delete_file_by_fcb_with_index(&fcb, None);
+    // delete_file_by_name的实现，在事务中执行
+    fn delete_file_by_name_in_transaction(&mut self, path: &str) -> FsResult<()> {
+        let (mut parent, name) = self.resolve_parent(path)?;
+        Directory::check_name(&name)?;
+        let index: usize = parent
+            .get_index_by_name(&name)
+            .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+        // 不能删除当前目录
+        if parent.files[index].first_block == self.cur_directory.files[1].first_block {
+            return Err(FsError::InUse(String::from(path)));
+        }
+        debug!("Trying to delete file in dir file list...");
+        self.delete_file_by_fcb_with_index(&parent.files[index].clone(), None)?;
+        parent.files.remove(index);
+        self.store_directory(&parent)
+    }
 
-        if res.is_err() {
-            self.cur_directory.files.push(fcb);
+    /// 通过路径删除文件或目录，目录中的所有文件和子目录都会被删除
+    pub fn delete_file_by_name_recursively(&mut self, path: &str) -> FsResult<()> {
+        self.transaction(|disk| disk.delete_file_by_name_recursively_in_transaction(path))
+    }
+
+    // delete_file_by_name_recursively的实现，在事务中执行
+    fn delete_file_by_name_recursively_in_transaction(&mut self, path: &str) -> FsResult<()> {
+        let (mut parent, name) = self.resolve_parent(path)?;
+        Directory::check_name(&name)?;
+        let index: usize = parent
+            .get_index_by_name(&name)
+            .ok_or_else(|| FsError::NotFound(String::from(path)))?;
+        let fcb: Fcb = parent.files[index].clone();
+        // 不能删除当前目录以及当前目录的上级目录
+        if let FileType::Directory = fcb.file_type {
+            if self.is_ancestor_of(fcb.first_block, self.cur_directory.files[1].first_block)? {
+                return Err(FsError::InUse(String::from(path)));
+            }
         }
+        debug!("Trying to delete '{}' recursively...", path);
+        self.delete_tree_by_fcb(&fcb)?;
+        parent.files.remove(index);
+        self.store_directory(&parent)
+    }
 
-        res
+    // 释放fcb以及（fcb为目录时）其下所有文件和子目录占用的块
+    fn delete_tree_by_fcb(&mut self, fcb: &Fcb) -> FsResult<()> {
+        if let FileType::Directory = fcb.file_type {
+            let dir: Directory = self.get_directory_by_fcb(fcb)?;
+            for child in &dir.files[2..] {
+                self.delete_tree_by_fcb(child)?;
+            }
+        }
+        self.delete_space_on_fat(fcb.first_block)?;
+        // 被删除的文件的句柄不能再使用
+        self.open_files.retain(|_, file| file.first_block != fcb.first_block);
+        Ok(())
+    }
+
+    // 判断ancestor_block对应的目录是否是block对应的目录本身或者其上级目录
+    fn is_ancestor_of(&self, ancestor_block: usize, block: usize) -> FsResult<bool> {
+        let mut block: usize = block;
+        // 链中出现环时层数不会超过块数量
+        for _ in 0..self.virtual_disk.fat().len() {
+            if block == ancestor_block {
+                return Ok(true);
+            }
+            if block == ROOT_BLOCK {
+                return Ok(false);
+            }
+            block = self.load_directory(block)?.files[0].first_block;
+        }
+
+        Err(FsError::CorruptChain(block))
     }
 
     // 首先要清除文件分配表中占用的块，数据区可以不清零，然后还要从父目录中删除对应的FCB
@@ -326,22 +668,18 @@ impl DiskInfo {
         &mut self,
         fcb: &Fcb,
         index: Option<usize>,
-    ) -> Result<(), String> {
+    ) -> FsResult<()> {
         if let FileType::Directory = fcb.file_type {
-            let dir: Directory = self.get_directory_by_fcb(fcb);
+            let dir: Directory = self.get_directory_by_fcb(fcb)?;
             if dir.files.len() > 2 {
-                return Err(String::from("[ERROR]\tThe Directory is not empty!"));
+                return Err(FsError::DirectoryNotEmpty(fcb.name.clone()));
             }
         }
-        print_debug_info();
-        println!(
-            "Trying to set all NotUsed clutster of file '{}' on FAT...",
-            fcb.name
-        );
+        debug!("Trying to set all NotUsed clutster of file '{}' on FAT...", fcb.name);
         // 直接返回删除文件的结果
-        if let Err(err) = self.delete_space_on_fat(fcb.first_block) {
-            return Err(err);
-        }
+        self.delete_space_on_fat(fcb.first_block)?;
+        // 被删除的文件的句柄不能再使用
+        self.open_files.retain(|_, file| file.first_block != fcb.first_block);
         // 若给定index非None，则删除目录下的FCB条目
         if let Some(i) = index {
             self.cur_directory.files.remove(i);
@@ -350,120 +688,1182 @@ impl DiskInfo {
         Ok(())
     }
 
-    // 切换到指定目录
-    pub fn change_current_directory(&mut self, name: &str) {
+    /// 切换到指定目录，path可以是绝对路径或相对路径
+    pub fn change_current_directory(&mut self, path: &str) -> FsResult<()> {
+        // 先找到要切换到的目录，目录不存在时不做任何修改
+        let dir: Directory = self.get_directory_by_path(path)?;
+
         // 先保存当前目录数据到硬盘
-        let dir_cloned: Directory = self.cur_directory.clone();
-        self.save_directory_to_disk(&dir_cloned);
-        // 通过name获取要切换到的目录fcb
-        let (_index, dir_fcb) = self.cur_directory.get_fcb_by_name(name).unwrap();
+        self.flush()?;
 
-        let dir: Directory = self.get_directory_by_fcb(dir_fcb);
         self.cur_directory = dir;
+        self.refresh_current_path()
+    }
+
+    /// 将当前目录和FAT通过日志写入磁盘，并把块设备缓存中的数据写到底层存储上
+    pub fn flush(&mut self) -> FsResult<()> {
+        // 事务提交时写入FAT和块设备缓存
+        self.transaction(|disk| {
+            let dir_cloned: Directory = disk.cur_directory.clone();
+            // 目录的修改通常已经写入磁盘，内容没有变化时不再重写，避免写时复制把目录的块越搬越散
+            let unchanged: bool = disk
+                .read_directory_from_disk(dir_cloned.files[1].first_block)
+                .map(|dir| bincode::serialize(&dir).unwrap() == bincode::serialize(&dir_cloned).unwrap())
+                .unwrap_or(false);
+            if unchanged {
+                return Ok(());
+            }
+            disk.save_directory_to_disk(&dir_cloned).map(|_| ())
+        })
+    }
+
+    // 重新计算当前目录的路径，上级目录被改名或移动后需要调用
+    fn refresh_current_path(&mut self) -> FsResult<()> {
+        self.cur_path = self.get_path_of_directory(&self.cur_directory)?;
+        Ok(())
+    }
+
+    // 从目录开始沿着".."向上直到根目录，在每一级父目录中找到自己的名字，拼出绝对路径
+    fn get_path_of_directory(&self, dir: &Directory) -> FsResult<String> {
+        let mut names: Vec<String> = Vec::new();
+        let mut block: usize = dir.files[1].first_block;
+        let mut parent_block: usize = dir.files[0].first_block;
+
+        while block != ROOT_BLOCK {
+            // 链中出现环时路径不会超过块数量
+            if names.len() > self.virtual_disk.fat().len() {
+                return Err(FsError::CorruptChain(block));
+            }
+            let parent: Directory = self.load_directory(parent_block)?;
+            let fcb: &Fcb = parent.files[2..]
+                .iter()
+                .find(|fcb| fcb.first_block == block)
+                .ok_or(FsError::CorruptChain(parent_block))?;
+            names.push(fcb.name.clone());
+            block = parent_block;
+            parent_block = parent.files[0].first_block;
+        }
+        names.reverse();
+
+        Ok(format!("/{}", names.join("/")))
     }
 
-    // 保存当前目录数据到硬盘，返回第一个块号——更改被保存，原目录文件将在磁盘上被覆盖
-    fn save_directory_to_disk(&mut self, dir: &Directory) -> usize {
-        print_debug_info();
-        println!("Trying to saving dir...");
+    // 保存目录数据到硬盘，返回第一个块号
+    // 目录的首块保持不变，这样父目录和子目录中记录的块号仍然有效
+    fn save_directory_to_disk(&mut self, dir: &Directory) -> FsResult<usize> {
+        debug!("Trying to saving dir...");
         let data = bincode::serialize(dir).unwrap();
-        let (insert_eof, blocks_needed) = DiskInfo::calc_blocks_needed_with_eof(data.len());
-        // 删除原先的块
-        self.delete_space_on_fat(self.cur_directory.files[1].first_block).unwrap();
-        // 分配新的块
-        let reallocated_blocks = self.allocate_free_space_on_fat(blocks_needed).unwrap();
-        self.virtual_disk.write_data_by_blocks_with_eof(
-            data.as_slice(),
-            reallocated_blocks.as_slice(),
-            insert_eof,
-        );
+        self.rewrite_data_on_disk(dir.files[1].first_block, &data)
+    }
+
+    // 用新数据替换以first_block开头的块链中的数据，首块不变（写时复制）
+    // 除首块外的数据写入新分配的块，然后在同一个事务中覆写首块并把它链接到新的块，最后才释放原来的块。
+    // 原来的块在事务提交前不会被修改，首块的覆写和FAT的修改通过日志一起生效，
+    // 所以中途断电时块链要么还是原来的数据，要么已经是完整的新数据
+    fn rewrite_data_on_disk(&mut self, first_block: usize, data: &[u8]) -> FsResult<usize> {
+        let blocks_needed: usize = self.calc_blocks_needed(data.len());
+        let old_blocks: Vec<usize> = self.get_file_blocks(first_block)?;
+        let new_blocks: Vec<usize> = match blocks_needed {
+            1 => Vec::new(),
+            _ => self.allocate_free_space_on_fat(blocks_needed - 1)?,
+        };
+        let blocks: Vec<usize> = [first_block].into_iter().chain(new_blocks.iter().copied()).collect();
+        if let Err(err) = self.virtual_disk.write_data_by_blocks(data, &blocks) {
+            self.release_blocks(&new_blocks);
+            return Err(err);
+        }
+
+        // 首块改为指向新的块，原来的块不再被引用，可以释放
+        let next: FatStatus = match new_blocks.first() {
+            Some(block) => FatStatus::NextBlock(*block),
+            None => FatStatus::EOF,
+        };
+        self.virtual_disk.set_fat(first_block, next);
+        self.release_blocks(&old_blocks[1..]);
+
+        Ok(first_block)
+    }
+
+    // 用长度不变的新数据直接覆写以first_block开头的块链，块链不变，长度改变时改为写时复制
+    fn overwrite_data_on_disk(&mut self, first_block: usize, data: &[u8]) -> FsResult<usize> {
+        let blocks_needed: usize = self.calc_blocks_needed(data.len());
+        let blocks: Vec<usize> = self.get_file_blocks(first_block)?;
+        if blocks.len() != blocks_needed {
+            return self.rewrite_data_on_disk(first_block, data);
+        }
+        self.virtual_disk.write_data_by_blocks(data, &blocks)?;
+
+        Ok(first_block)
+    }
 
-        reallocated_blocks[0]
+    /// 文件或目录改名，old和new都可以是路径
+    /// new与old不在同一目录时，相当于移动并改名
+    pub fn rename_file_by_name(&mut self, old: &str, new: &str) -> FsResult<()> {
+        self.transaction(|disk| disk.rename_file_by_name_in_transaction(old, new))
     }
 
-    // 文件改名
-    // 目录改名要复杂一些，这里没实现
-    pub fn rename_file_by_name(&mut self, old: &str, new: &str) {
-        let (index, fcb) = self.cur_directory.get_fcb_by_name(old).unwrap();
+    // rename_file_by_name的实现，在事务中执行
+    fn rename_file_by_name_in_transaction(&mut self, old: &str, new: &str) -> FsResult<()> {
+        let (mut src_dir, old_name) = self.resolve_parent(old)?;
+        let (mut des_dir, new_name) = self.resolve_parent(new)?;
+        Directory::check_name(&old_name)?;
+        Directory::check_name(&new_name)?;
+        let index: usize = src_dir
+            .get_index_by_name(&old_name)
+            .ok_or_else(|| FsError::NotFound(String::from(old)))?;
+        let fcb: Fcb = src_dir.files[index].clone();
+        let same_dir: bool = src_dir.files[1].first_block == des_dir.files[1].first_block;
+
+        if same_dir && old_name == new_name {
+            return Ok(());
+        }
+        if des_dir.get_fcb_by_name(&new_name).is_some() {
+            return Err(FsError::AlreadyExists(String::from(new)));
+        }
+        // 目录不能移动到自己或者自己的子目录中
+        if let FileType::Directory = fcb.file_type {
+            if !same_dir && self.is_ancestor_of(fcb.first_block, des_dir.files[1].first_block)? {
+                return Err(FsError::IntoItself(String::from(old)));
+            }
+        }
+
         let new_fcb: Fcb = Fcb {
-            name: String::from(new),
-            ..fcb.to_owned()
+            name: new_name.clone(),
+            ..fcb.clone()
         };
-        self.cur_directory.files[index] = new_fcb;
-    }
-
-    // 移动文件
-    pub fn movie_file_by_name(&mut self, file_name: &str, path: &str) {
-        let index = self.cur_directory.get_index_by_name(file_name).unwrap();
-        // 从当前目录中删除fcb
-        let fcb: Fcb = self.cur_directory.files.remove(index);
-        self.save_directory_to_disk(&self.cur_directory.clone());
-        
-        let dir_names: Vec<&str> = path.split("/").collect();
-        let mut cur_directory = self.cur_directory.clone();
-        for dir_name in dir_names {
-            if dir_name == "" {
-                continue;
+        if same_dir {
+            src_dir.files[index] = new_fcb;
+            self.store_directory(&src_dir)?;
+        } else {
+            // 从原目录中删除fcb，加入目标目录
+            // 先写目标目录：它可能需要新的块，空间不足时原目录还没有被修改
+            src_dir.files.remove(index);
+            des_dir.files.push(new_fcb);
+            self.store_directory(&des_dir)?;
+            self.store_directory(&src_dir)?;
+            // 打开的文件改为在目标目录中查找
+            for file in self.open_files.values_mut() {
+                if file.first_block == fcb.first_block {
+                    file.parent_block = des_dir.files[1].first_block;
+                }
             }
-            let (_, dir_fcb) = cur_directory.get_fcb_by_name(dir_name).unwrap();
-            cur_directory = self.get_directory_by_fcb(dir_fcb);
-        }
-        cur_directory.files.push(fcb);
-        let data: Vec<u8> = bincode::serialize(&cur_directory).unwrap();
-        let (insert_eof, blocks_needed) = DiskInfo::calc_blocks_needed_with_eof(data.len());
-        // 删除原先的块
-        self.delete_space_on_fat(cur_directory.files[1].first_block).unwrap();
-        // 分配新的块
-        let reallocated_blocks: Vec<usize> = self.allocate_free_space_on_fat(blocks_needed).unwrap();
-        self.virtual_disk.write_data_by_blocks_with_eof(
-            data.as_slice(),
-            reallocated_blocks.as_slice(),
-            insert_eof,
-        );
+        }
+        // 目录的数据中也记录了目录名，移动后还要修改".."指向新的父目录
+        if let FileType::Directory = fcb.file_type {
+            let mut dir: Directory = self.get_directory_by_fcb(&fcb)?;
+            dir.name = new_name;
+            dir.files[0].first_block = des_dir.files[1].first_block;
+            self.store_directory(&dir)?;
+        }
+        self.refresh_current_path()
+    }
+
+    /// 把文件old改名为new，new是已经存在的文件时在同一个事务中替换掉它
+    pub fn replace_file_by_name(&mut self, old: &str, new: &str) -> FsResult<()> {
+        self.transaction(|disk| {
+            let (_src_dir, src) = disk.resolve_fcb(old)?;
+            match disk.resolve_fcb(new) {
+                Ok((_des_dir, des)) if src.file_type == FileType::File && des.file_type == FileType::File => {
+                    // 同一个文件不需要替换
+                    if src.first_block == des.first_block {
+                        return Ok(());
+                    }
+                    disk.delete_file_by_name_in_transaction(new)?;
+                }
+                _ => {}
+            }
+            disk.rename_file_by_name_in_transaction(old, new)
+        })
     }
 
-    // 获取部分磁盘信息
-    // 返回 磁盘总大小/Byte，已分配块数量、未分配块的数量
+    /// 移动文件或目录，file_name是要移动的文件的路径，path是目标目录的路径
+    pub fn movie_file_by_name(&mut self, file_name: &str, path: &str) -> FsResult<()> {
+        let (_src_dir, name) = self.resolve_parent(file_name)?;
+        // 先检查目标目录，给出更准确的错误信息
+        self.get_directory_by_path(path)?;
+        self.rename_file_by_name(file_name, &format!("{}/{}", path, name))
+    }
+
+    /// 获取部分磁盘信息
+    /// 返回 磁盘总大小/Byte，已分配块数量、未分配块的数量
     pub fn get_disk_info(&self) -> (usize, usize, usize) {
-        let disk_size: usize = BLOCK_SIZE * BLOCK_COUNT;
-        let mut num_used: usize = 0usize;
-        let mut num_not_used: usize = 0usize;
+        let geometry: Geometry = self.virtual_disk.geometry();
+        let disk_size: usize = geometry.block_size * geometry.block_count;
+        // 空闲块数量由空闲块位图记录，不需要遍历FAT
+        let num_not_used: usize = self.virtual_disk.free_block_count();
+        let num_used: usize = geometry.block_count - num_not_used;
+
+        (disk_size, num_used, num_not_used)
+    }
+
+    /// 生成磁盘块分布图：从根目录开始遍历所有文件和目录，记录每一块属于哪个文件以及每个文件的碎片数
+    pub fn block_map(&self) -> FsResult<BlockMap> {
+        let fat: &[FatStatus] = self.virtual_disk.fat();
+        let mut blocks: Vec<BlockState> = fat
+            .iter()
+            .map(|fat_item| match fat_item {
+                FatStatus::UnUsed => BlockState::Free,
+                _ => BlockState::Orphan,
+            })
+            .collect();
+        let mut files: Vec<FileFragments> = Vec::new();
+
+        // 待访问的文件：路径和FCB，按深度优先的顺序访问
+        let root: Fcb = Fcb {
+            name: String::from("/"),
+            file_type: FileType::Directory,
+            first_block: ROOT_BLOCK,
+            length: 0,
+        };
+        let mut pending: Vec<(String, Fcb)> = vec![(String::from("/"), root)];
+        while let Some((path, fcb)) = pending.pop() {
+            match blocks.get(fcb.first_block) {
+                None => return Err(FsError::CorruptChain(fcb.first_block)),
+                // 块已经属于其他文件，不再重复统计，避免目录树中有环时无法结束
+                Some(BlockState::Used(_)) | Some(BlockState::Eof(_)) => continue,
+                _ => {}
+            }
+            let chain: Vec<usize> = self.get_file_blocks(fcb.first_block)?;
+            let index: usize = files.len();
+            for (i, block) in chain.iter().enumerate() {
+                blocks[*block] = if i == chain.len() - 1 {
+                    BlockState::Eof(index)
+                } else {
+                    BlockState::Used(index)
+                };
+            }
+            // 相邻两块不连续时就多一个连续段
+            let fragments: usize = 1 + chain.windows(2).filter(|pair| pair[1] != pair[0] + 1).count();
+            files.push(FileFragments {
+                path: path.clone(),
+                file_type: fcb.file_type,
+                first_block: fcb.first_block,
+                blocks: chain.len(),
+                fragments,
+            });
 
-        for fat_item in &self.virtual_disk.fat {
-            match fat_item {
-                FatStatus::NextBlock(_no) => num_used += 1,
-                FatStatus::EOF => num_used += 1,
-                FatStatus::UnUsed => num_not_used += 1,
+            if let FileType::Directory = fcb.file_type {
+                let dir: Directory = self.get_directory_by_fcb(&fcb)?;
+                // 倒序压入，先访问目录中靠前的文件
+                for child in dir.files[2..].iter().rev() {
+                    let child_path: String = if path == "/" {
+                        format!("/{}", child.name)
+                    } else {
+                        format!("{}/{}", path, child.name)
+                    };
+                    pending.push((child_path, child.clone()));
+                }
             }
         }
 
-        (disk_size, num_used, num_not_used)
+        Ok(BlockMap {
+            blocks,
+            files,
+            stats: self.fragmentation_stats(),
+        })
     }
 
-    // FCB的移动
-    // 这个也没用上
-    pub fn move_fcb_between_dirs_by_name(&mut self, name: &str, des_dir: &mut Directory) {
-        let fcb = self
-            .cur_directory
+    /// 碎片整理：按目录树深度优先的顺序，把每个文件和目录依次移动到从0号块开始的连续块中
+    /// 并修改FAT中的链接、父目录中的FCB以及目录的"."和".."
+    /// 每移动完一个文件调用一次keep_going，返回false时停止。停止时文件系统仍然是一致的，之后可以继续整理
+    pub fn defragment<F: FnMut(&DefragReport) -> bool>(&mut self, mut keep_going: F) -> FsResult<DefragReport> {
+        info!("Defragmenting...");
+        // 当前目录的修改先写入磁盘，整理过程中所有目录都直接在磁盘上读写
+        self.flush()?;
+        let mut nodes: Vec<TreeNode> = self.collect_tree_nodes()?;
+        // 当前目录对应的节点，整理后通过它找到当前目录新的首块
+        let cur_node: Option<usize> = nodes
+            .iter()
+            .position(|node| node.first_block == self.cur_directory.files[1].first_block);
+        // 每一块的上一块，修改指向被移动的块的链接时使用
+        let mut pred: Vec<Option<usize>> = self.block_predecessors();
+
+        let mut report: DefragReport = DefragReport {
+            files_total: nodes.len(),
+            ..DefragReport::default()
+        };
+        let mut next_block: usize = 0;
+        for index in 0..nodes.len() {
+            let chain: Vec<usize> = self.get_file_blocks(nodes[index].first_block)?;
+            let start: usize = next_block;
+            next_block += chain.len();
+            report.files_checked += 1;
+            if chain.iter().copied().eq(start..next_block) {
+                continue;
+            }
+
+            // 依次把第i块和目标位置上的块交换，得到每一块要移动到的位置
+            let mut position: HashMap<usize, usize> = HashMap::new();    // 原块号 -> 新块号
+            let mut occupant: HashMap<usize, usize> = HashMap::new();    // 新块号 -> 原块号
+            for (i, block) in chain.iter().enumerate() {
+                let target: usize = start + i;
+                let current: usize = *position.get(block).unwrap_or(block);
+                if current == target {
+                    continue;
+                }
+                let other: usize = *occupant.get(&target).unwrap_or(&target);
+                position.insert(*block, target);
+                occupant.insert(target, *block);
+                position.insert(other, current);
+                occupant.insert(current, other);
+            }
+            position.retain(|from, to| from != to);
+
+            // 每个文件的移动是一个事务，中途断电时这个文件要么已经移动完，要么还在原来的位置
+            let nodes_before: Vec<TreeNode> = nodes.clone();
+            let result: FsResult<usize> = self.transaction(|disk| {
+                let blocks_moved: usize = disk.relocate_blocks(&position, &mut pred)?;
+                disk.remap_directory_entries(&position, &mut nodes)?;
+                Ok(blocks_moved)
+            });
+            match result {
+                Ok(blocks_moved) => report.blocks_moved += blocks_moved,
+                Err(FsError::TransactionTooLarge(_)) => {
+                    // 要移动的块太多，日志区放不下，这个文件保持原样
+                    nodes = nodes_before;
+                    pred = self.block_predecessors();
+                    report.files_skipped += 1;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+            report.files_moved += 1;
+            if !keep_going(&report) {
+                break;
+            }
+        }
+        report.completed = report.files_checked == report.files_total;
+
+        // 当前目录可能被移动或修改过，重新从磁盘读取
+        let cur_block: usize = match cur_node {
+            Some(index) => nodes[index].first_block,
+            None => ROOT_BLOCK,
+        };
+        self.cur_directory = self.read_directory_from_disk(cur_block)?;
+        self.next_fit_cursor = next_block;
+        self.flush()?;
+        Ok(report)
+    }
+
+    // 每一块在块链中的上一块
+    fn block_predecessors(&self) -> Vec<Option<usize>> {
+        let mut pred: Vec<Option<usize>> = vec![None; self.virtual_disk.fat().len()];
+        for (block, fat_item) in self.virtual_disk.fat().iter().enumerate() {
+            if let FatStatus::NextBlock(next) = fat_item {
+                if let Some(prev) = pred.get_mut(*next) {
+                    *prev = Some(block);
+                }
+            }
+        }
+        pred
+    }
+
+    // 从根目录开始按深度优先的顺序列出所有文件和目录
+    fn collect_tree_nodes(&self) -> FsResult<Vec<TreeNode>> {
+        let mut nodes: Vec<TreeNode> = Vec::new();
+        let mut visited: HashSet<usize> = HashSet::new();
+        // 待访问的节点：首块、是否是目录、父目录的下标
+        let mut pending: Vec<(usize, bool, Option<usize>)> = vec![(ROOT_BLOCK, true, None)];
+        while let Some((first_block, is_directory, parent)) = pending.pop() {
+            // 被多个FCB共用的块链只整理一次
+            if !visited.insert(first_block) {
+                continue;
+            }
+            let index: usize = nodes.len();
+            nodes.push(TreeNode {
+                first_block,
+                is_directory,
+                parent,
+                child_directories: Vec::new(),
+            });
+            if let Some(parent) = parent {
+                if is_directory {
+                    nodes[parent].child_directories.push(index);
+                }
+            }
+            if is_directory {
+                let dir: Directory = self.read_directory_from_disk(first_block)?;
+                for child in dir.files[2..].iter().rev() {
+                    pending.push((child.first_block, child.file_type == FileType::Directory, Some(index)));
+                }
+            }
+        }
+        Ok(nodes)
+    }
+
+    // 按position移动块中的数据并修改FAT，返回移动了数据的块数
+    fn relocate_blocks(&mut self, position: &HashMap<usize, usize>, pred: &mut [Option<usize>]) -> FsResult<usize> {
+        let map = |block: usize| *position.get(&block).unwrap_or(&block);
+
+        // 先读出所有要移动的数据再写入，交换的块不会互相覆盖
+        let mut buffers: Vec<(usize, Vec<u8>)> = Vec::new();
+        for (from, to) in position {
+            if !matches!(self.virtual_disk.fat()[*from], FatStatus::UnUsed) {
+                buffers.push((*to, self.virtual_disk.read_data_by_block(*from)?));
+            }
+        }
+        for (to, data) in &buffers {
+            self.virtual_disk.insert_data_by_block(data, *to)?;
+        }
+
+        // 被移动的块以及它们的上一块、下一块在FAT中的项都要改
+        let mut affected: BTreeSet<usize> = BTreeSet::new();
+        for block in position.keys() {
+            affected.insert(*block);
+            if let Some(prev) = pred[*block] {
+                affected.insert(prev);
+            }
+            if let FatStatus::NextBlock(next) = self.virtual_disk.fat()[*block] {
+                affected.insert(next);
+            }
+        }
+        let updates: Vec<(usize, FatStatus, Option<usize>)> = affected
+            .iter()
+            .map(|block| {
+                let status: FatStatus = match &self.virtual_disk.fat()[*block] {
+                    FatStatus::NextBlock(next) => FatStatus::NextBlock(map(*next)),
+                    other => other.clone(),
+                };
+                (map(*block), status, pred[*block].map(map))
+            })
+            .collect();
+        for (block, status, prev) in updates {
+            self.virtual_disk.set_fat(block, status);
+            pred[block] = prev;
+        }
+        Ok(buffers.len())
+    }
+
+    // 块被移动后，修改所有指向被移动的首块的FCB、"."和".."
+    fn remap_directory_entries(&mut self, position: &HashMap<usize, usize>, nodes: &mut [TreeNode]) -> FsResult<()> {
+        let map = |block: usize| *position.get(&block).unwrap_or(&block);
+
+        // 需要修改的目录：被移动的文件的父目录，被移动的目录自己和它的子目录
+        let mut dirs: BTreeSet<usize> = BTreeSet::new();
+        for (index, node) in nodes.iter().enumerate() {
+            if position.contains_key(&node.first_block) {
+                if let Some(parent) = node.parent {
+                    dirs.insert(parent);
+                }
+                if node.is_directory {
+                    dirs.insert(index);
+                    dirs.extend(node.child_directories.iter().copied());
+                }
+            }
+        }
+        for node in nodes.iter_mut() {
+            node.first_block = map(node.first_block);
+        }
+        for file in self.open_files.values_mut() {
+            file.parent_block = map(file.parent_block);
+            file.first_block = map(file.first_block);
+        }
+
+        for index in dirs {
+            let first_block: usize = nodes[index].first_block;
+            let mut dir: Directory = self.read_directory_from_disk(first_block)?;
+            for fcb in dir.files.iter_mut() {
+                fcb.first_block = map(fcb.first_block);
+            }
+            // 块号定长存储，目录的数据长度不变，直接覆写，不能改变刚整理好的块链
+            let data: Vec<u8> = bincode::serialize(&dir).unwrap();
+            self.overwrite_data_on_disk(first_block, &data)?;
+        }
+        Ok(())
+    }
+
+    /// 检查文件系统的一致性：从根目录开始遍历所有文件和目录，检查块链、FCB中记录的长度、
+    /// 目录的"."和".."，以及已被使用但不属于任何文件的块
+    /// repair为true时修复能修复的问题：截断损坏的块链，复制和其他文件共用的块链，
+    /// 修正长度和"."、".."，删除无法读取的目录项，释放泄漏的块
+    pub fn fsck(&mut self, repair: bool) -> FsResult<FsckReport> {
+        info!("Checking file system{}...", if repair { " and repairing" } else { "" });
+        // 当前目录的修改先写入磁盘，检查和修复都直接在磁盘上进行
+        self.flush()?;
+        let cur_block: usize = self.cur_directory.files[1].first_block;
+
+        let mut report: FsckReport = FsckReport::default();
+        let mut findings: Vec<FsckFinding> = self.fsck_check()?;
+        report.problems = findings.iter().map(|finding| finding.problem.clone()).collect();
+        if repair {
+            // 修复一个问题可能暴露出新的问题（例如截断块链后泄漏的块），重复检查直到没有可以修复的问题
+            for _ in 0..FSCK_MAX_PASSES {
+                if findings.is_empty() {
+                    break;
+                }
+                // 每一轮修复是一个事务
+                let repaired: usize = self.transaction(|disk| disk.fsck_repair(&findings))?;
+                if repaired == 0 {
+                    break;
+                }
+                report.repaired += repaired;
+                findings = self.fsck_check()?;
+            }
+
+            // 修复时可能修改了当前目录，重新从磁盘读取，当前目录已被删除时回到根目录
+            self.cur_directory = match self.read_directory_from_disk(cur_block) {
+                Ok(dir) if dir.files.len() >= 2 && dir.files[1].first_block == cur_block => dir,
+                _ => self.read_directory_from_disk(ROOT_BLOCK)?,
+            };
+            if self.refresh_current_path().is_err() {
+                self.cur_directory = self.read_directory_from_disk(ROOT_BLOCK)?;
+                self.refresh_current_path()?;
+            }
+            self.flush()?;
+        }
+        report.remaining = findings.into_iter().map(|finding| finding.problem).collect();
+
+        Ok(report)
+    }
+
+    // 从根目录开始按深度优先的顺序检查所有文件和目录，返回发现的问题
+    fn fsck_check(&self) -> FsResult<Vec<FsckFinding>> {
+        let fat: &[FatStatus] = self.virtual_disk.fat();
+        let mut findings: Vec<FsckFinding> = Vec::new();
+        // 每一块属于paths中的第几个文件
+        let mut owner: Vec<Option<usize>> = vec![None; fat.len()];
+        let mut paths: Vec<String> = Vec::new();
+
+        // 待检查的文件：路径、FCB、父目录的首块，根目录没有父目录
+        let root: Fcb = Fcb {
+            name: String::from("/"),
+            file_type: FileType::Directory,
+            first_block: ROOT_BLOCK,
+            length: 0,
+        };
+        let mut pending: Vec<(String, Fcb, Option<usize>)> = vec![(String::from("/"), root, None)];
+        while let Some((path, fcb, parent)) = pending.pop() {
+            let location: Option<(usize, String)> = parent.map(|parent_block| (parent_block, fcb.name.clone()));
+            // 根目录的".."指向自己
+            let parent_block: usize = parent.unwrap_or(ROOT_BLOCK);
+            let index: usize = paths.len();
+            paths.push(path.clone());
+            let is_directory: bool = fcb.file_type == FileType::Directory;
+            let finding = |problem: FsckProblem, chain: Vec<usize>| FsckFinding {
+                problem,
+                location: location.clone(),
+                is_directory,
+                chain,
+            };
+
+            // 沿着FAT走完整条块链，遇到问题时停下，chain中只保留没有问题的部分
+            let mut chain: Vec<usize> = Vec::new();
+            let mut block: usize = fcb.first_block;
+            let problem: Option<FsckProblem> = loop {
+                match (fat.get(block), owner.get(block).copied().flatten()) {
+                    (None, _) => break Some(FsckProblem::OutOfRange { path: path.clone(), next: block }),
+                    (Some(FatStatus::UnUsed), _) => break Some(FsckProblem::UnusedInChain { path: path.clone(), block }),
+                    (_, Some(other)) if other == index => break Some(FsckProblem::Cycle { path: path.clone(), block }),
+                    (_, Some(other)) => {
+                        break Some(FsckProblem::SharedChain { path: path.clone(), other: paths[other].clone(), block })
+                    }
+                    (Some(fat_item), None) => {
+                        owner[block] = Some(index);
+                        chain.push(block);
+                        match fat_item {
+                            FatStatus::NextBlock(next) => block = *next,
+                            _ => break None,
+                        }
+                    }
+                }
+            };
+            if let Some(problem) = problem {
+                findings.push(finding(problem, chain));
+                continue;
+            }
+
+            if !is_directory {
+                let blocks_needed: usize = self.calc_blocks_needed(fcb.length);
+                if blocks_needed != chain.len() {
+                    let problem = FsckProblem::LengthMismatch { path, length: fcb.length, blocks: chain.len() };
+                    findings.push(finding(problem, chain));
+                }
+                continue;
+            }
+
+            let dir: Directory = match self.read_directory_from_disk(fcb.first_block) {
+                Ok(dir) if dir.files.len() >= 2 => dir,
+                _ => {
+                    findings.push(finding(FsckProblem::UnreadableDirectory { path }, chain));
+                    continue;
+                }
+            };
+            for (entry, expected) in [(&dir.files[0], parent_block), (&dir.files[1], fcb.first_block)] {
+                if entry.first_block != expected {
+                    let problem = FsckProblem::BrokenLink {
+                        path: path.clone(),
+                        name: entry.name.clone(),
+                        expected,
+                        found: entry.first_block,
+                    };
+                    findings.push(finding(problem, chain.clone()));
+                }
+            }
+            // 倒序压入，先检查目录中靠前的文件
+            for child in dir.files[2..].iter().rev() {
+                let child_path: String = if path == "/" {
+                    format!("/{}", child.name)
+                } else {
+                    format!("{}/{}", path, child.name)
+                };
+                pending.push((child_path, child.clone(), Some(fcb.first_block)));
+            }
+        }
+
+        let leaked: Vec<usize> = (0..fat.len())
+            .filter(|block| !matches!(fat[*block], FatStatus::UnUsed) && owner[*block].is_none())
+            .collect();
+        if !leaked.is_empty() {
+            findings.push(FsckFinding {
+                problem: FsckProblem::LeakedBlocks { blocks: leaked.clone() },
+                location: None,
+                is_directory: false,
+                chain: leaked,
+            });
+        }
+        Ok(findings)
+    }
+
+    // 修复fsck_check发现的问题，返回修复了多少个
+    fn fsck_repair(&mut self, findings: &[FsckFinding]) -> FsResult<usize> {
+        let mut repaired: usize = 0;
+        for finding in findings {
+            debug!("Repairing: {}", finding.problem);
+            let fixed: bool = match &finding.problem {
+                FsckProblem::SharedChain { .. } if !finding.is_directory && finding.location.is_some() => {
+                    // 把共用的数据复制一份，文件改用新的块链，自己独占的前半段释放
+                    let (parent_block, name) = finding.location.clone().unwrap();
+                    let fcb: Fcb = self.fsck_find_entry(parent_block, &name)?;
+                    match self.get_file_data(fcb.first_block, fcb.length) {
+                        Ok(data) => {
+                            let new_block: usize = self.write_data_to_disk(&data)?;
+                            self.release_blocks(&finding.chain);
+                            self.fsck_update_entry(parent_block, &name, |fcb| fcb.first_block = new_block)?
+                        }
+                        Err(_) => self.fsck_truncate_chain(finding)?,
+                    }
+                }
+                FsckProblem::OutOfRange { .. }
+                | FsckProblem::UnusedInChain { .. }
+                | FsckProblem::Cycle { .. }
+                | FsckProblem::SharedChain { .. } => self.fsck_truncate_chain(finding)?,
+                FsckProblem::LengthMismatch { length, .. } => {
+                    // 块链比长度需要的长时，多出的块从链上去掉，之后作为泄漏的块释放；
+                    // 块链比长度需要的短时，以块链中能读出的数据为准修正长度
+                    let (parent_block, name) = finding.location.clone().unwrap();
+                    let length: usize = (*length).min(finding.chain.len() * self.virtual_disk.block_size());
+                    let blocks_needed: usize = self.calc_blocks_needed(length);
+                    if blocks_needed < finding.chain.len() {
+                        self.virtual_disk.set_fat(finding.chain[blocks_needed - 1], FatStatus::EOF);
+                    }
+                    self.fsck_update_entry(parent_block, &name, |fcb| fcb.length = length)?
+                }
+                FsckProblem::BrokenLink { name, expected, .. } => {
+                    let first_block: usize = finding.chain[0];
+                    let mut dir: Directory = self.read_directory_from_disk(first_block)?;
+                    let index: usize = if name == ".." { 0 } else { 1 };
+                    dir.files[index].first_block = *expected;
+                    let data: Vec<u8> = bincode::serialize(&dir).unwrap();
+                    self.rewrite_data_on_disk(first_block, &data)?;
+                    true
+                }
+                FsckProblem::UnreadableDirectory { .. } => match &finding.location {
+                    // 目录项删除后，目录占用的块在下一次检查时作为泄漏的块释放
+                    Some((parent_block, name)) => self.fsck_remove_entry(*parent_block, name)?,
+                    None => false,
+                },
+                FsckProblem::LeakedBlocks { blocks } => {
+                    self.release_blocks(blocks);
+                    true
+                }
+            };
+            if fixed {
+                repaired += 1;
+            }
+        }
+        Ok(repaired)
+    }
+
+    // 在损坏的位置截断块链，链中没有可用的块时删除目录项
+    fn fsck_truncate_chain(&mut self, finding: &FsckFinding) -> FsResult<bool> {
+        match (finding.chain.last(), &finding.location) {
+            (Some(last), _) => {
+                self.virtual_disk.set_fat(*last, FatStatus::EOF);
+                Ok(true)
+            }
+            (None, Some((parent_block, name))) => self.fsck_remove_entry(*parent_block, name),
+            (None, None) => Ok(false),
+        }
+    }
+
+    // 在首块为parent_block的目录中查找名为name的目录项
+    fn fsck_find_entry(&self, parent_block: usize, name: &str) -> FsResult<Fcb> {
+        let dir: Directory = self.read_directory_from_disk(parent_block)?;
+        dir.files[2..]
+            .iter()
+            .find(|fcb| fcb.name == name)
+            .cloned()
+            .ok_or_else(|| FsError::NotFound(String::from(name)))
+    }
+
+    // 修改首块为parent_block的目录中名为name的目录项，返回是否找到了这一项
+    fn fsck_update_entry<F: FnOnce(&mut Fcb)>(&mut self, parent_block: usize, name: &str, update: F) -> FsResult<bool> {
+        let mut dir: Directory = self.read_directory_from_disk(parent_block)?;
+        match dir.files[2..].iter_mut().find(|fcb| fcb.name == name) {
+            Some(fcb) => update(fcb),
+            None => return Ok(false),
+        }
+        // 父目录的"."可能也是坏的，直接按parent_block写回
+        let data: Vec<u8> = bincode::serialize(&dir).unwrap();
+        self.rewrite_data_on_disk(parent_block, &data)?;
+        Ok(true)
+    }
+
+    // 删除首块为parent_block的目录中名为name的目录项，返回是否找到了这一项
+    fn fsck_remove_entry(&mut self, parent_block: usize, name: &str) -> FsResult<bool> {
+        let mut dir: Directory = self.read_directory_from_disk(parent_block)?;
+        match dir.files.iter().skip(2).position(|fcb| fcb.name == name) {
+            Some(index) => dir.files.remove(index + 2),
+            None => return Ok(false),
+        };
+        let data: Vec<u8> = bincode::serialize(&dir).unwrap();
+        self.rewrite_data_on_disk(parent_block, &data)?;
+        Ok(true)
+    }
+
+    /// 检查所有已使用的块的校验和，找出数据被意外修改的块以及它们所属的文件
+    /// 镜像没有校验和区时不做检查
+    pub fn scrub(&mut self) -> FsResult<ScrubReport> {
+        info!("Scrubbing all used blocks...");
+        // 当前目录的修改先写入磁盘
+        self.flush()?;
+        let mut report: ScrubReport = ScrubReport {
+            has_checksums: self.virtual_disk.has_checksums(),
+            ..ScrubReport::default()
+        };
+        if !report.has_checksums {
+            return Ok(report);
+        }
+
+        // 每一块属于哪个文件。块链或目录损坏时只记录能读到的部分，由fsck负责检查
+        let mut owners: HashMap<usize, String> = HashMap::new();
+        let mut pending: Vec<(String, usize, bool)> = vec![(String::from("/"), ROOT_BLOCK, true)];
+        while let Some((path, first_block, is_directory)) = pending.pop() {
+            let blocks: Vec<usize> = match self.get_file_blocks(first_block) {
+                Ok(blocks) => blocks,
+                Err(_) => continue,
+            };
+            // 被多个FCB共用的块链只访问一次
+            if owners.contains_key(&first_block) {
+                continue;
+            }
+            for block in blocks {
+                owners.entry(block).or_insert_with(|| path.clone());
+            }
+            if !is_directory {
+                continue;
+            }
+            if let Ok(dir) = self.read_directory_from_disk(first_block) {
+                for fcb in dir.files[2..].iter() {
+                    let child_path: String = format!("{}/{}", path.trim_end_matches('/'), fcb.name);
+                    pending.push((child_path, fcb.first_block, fcb.file_type == FileType::Directory));
+                }
+            }
+        }
+
+        for block in 0..self.virtual_disk.fat().len() {
+            if let FatStatus::UnUsed = self.virtual_disk.fat()[block] {
+                continue;
+            }
+            report.blocks_checked += 1;
+            match self.virtual_disk.read_data_by_block(block) {
+                Ok(_) => {}
+                Err(FsError::BadChecksum(..)) => report.bad_blocks.push(BadBlock {
+                    block,
+                    path: owners.get(&block).cloned(),
+                }),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(report)
+    }
+
+    /// 打开文件，返回之后读写文件使用的句柄，path可以是绝对路径或相对路径
+    /// 文件不存在且flags.create为true时创建空文件，flags.truncate为true时把文件长度截断为0
+    pub fn open_file(&mut self, path: &str, flags: OpenFlags) -> FsResult<FileHandle> {
+        info!("Opening file '{}'...", path);
+        // 创建、截断和追加都会修改文件，需要以可写的方式打开
+        if !flags.write && (!flags.read || flags.create || flags.truncate || flags.append) {
+            return Err(FsError::AccessDenied(String::from(path)));
+        }
+        let (parent, fcb) = match self.resolve_fcb(path) {
+            Err(FsError::NotFound(_)) if flags.create => {
+                self.create_file_with_data(path, &[])?;
+                self.resolve_fcb(path)?
+            }
+            result => result?,
+        };
+        if fcb.file_type != FileType::File {
+            return Err(FsError::IsADirectory(String::from(path)));
+        }
+
+        let handle: FileHandle = FileHandle(self.next_handle);
+        self.next_handle += 1;
+        self.open_files.insert(
+            handle.0,
+            OpenFile {
+                parent_block: parent.files[1].first_block,
+                first_block: fcb.first_block,
+                flags,
+                position: 0,
+            },
+        );
+        if flags.truncate {
+            if let Err(err) = self.truncate(handle, 0) {
+                self.open_files.remove(&handle.0);
+                return Err(err);
+            }
+        }
+        debug!("Opened '{}' as handle {}.", path, handle);
+        Ok(handle)
+    }
+
+    /// 关闭文件句柄
+    pub fn close(&mut self, handle: FileHandle) -> FsResult<()> {
+        self.open_files
+            .remove(&handle.0)
+            .map(|_| ())
+            .ok_or(FsError::BadHandle(handle.0))
+    }
+
+    // 通过句柄找到打开的文件
+    fn get_open_file(&self, handle: FileHandle) -> FsResult<&OpenFile> {
+        self.open_files.get(&handle.0).ok_or(FsError::BadHandle(handle.0))
+    }
+
+    // 通过句柄找到文件所在的目录和文件的FCB在目录中的位置
+    fn locate_open_file(&self, handle: FileHandle) -> FsResult<(Directory, usize)> {
+        let file: &OpenFile = self.get_open_file(handle)?;
+        let dir: Directory = self.load_directory(file.parent_block)?;
+        let index: usize = dir
             .files
-            .remove(self.cur_directory.get_index_by_name(name).unwrap());
-        des_dir.files.push(fcb);
+            .iter()
+            .position(|fcb| fcb.file_type == FileType::File && fcb.first_block == file.first_block)
+            .ok_or(FsError::BadHandle(handle.0))?;
+        Ok((dir, index))
+    }
+
+    // 检查打开文件时是否允许读或写，返回文件所在的目录和FCB在目录中的位置
+    fn check_access(&self, handle: FileHandle, write: bool) -> FsResult<(Directory, usize)> {
+        let (dir, index) = self.locate_open_file(handle)?;
+        let flags: OpenFlags = self.get_open_file(handle)?.flags;
+        if (write && !flags.write) || (!write && !flags.read) {
+            return Err(FsError::AccessDenied(dir.files[index].name.clone()));
+        }
+        Ok((dir, index))
+    }
+
+    /// 打开的文件当前的长度
+    pub fn file_length(&self, handle: FileHandle) -> FsResult<u64> {
+        let (dir, index) = self.locate_open_file(handle)?;
+        Ok(dir.files[index].length as u64)
+    }
+
+    /// 从文件的offset处读取数据到buf中，返回读取的字节数，offset在文件末尾之后时返回0
+    /// 只读取需要的块，不读出整个文件
+    pub fn read_at(&self, handle: FileHandle, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let (dir, index) = self.check_access(handle, false)?;
+        let fcb: &Fcb = &dir.files[index];
+        let offset: usize = match usize::try_from(offset) {
+            Ok(offset) if offset < fcb.length => offset,
+            _ => return Ok(0),
+        };
+        let count: usize = buf.len().min(fcb.length - offset);
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let block_size: usize = self.virtual_disk.block_size();
+        let blocks: Vec<usize> = self.get_file_blocks(fcb.first_block).map_err(|err| err.in_file(&fcb.name))?;
+        if blocks.len() * block_size < fcb.length {
+            // 块链比记录的长度短
+            return Err(FsError::CorruptChain(fcb.first_block));
+        }
+        let end: usize = offset + count;
+        let last: usize = (end - 1) / block_size;
+        for (i, block) in blocks.iter().enumerate().take(last + 1).skip(offset / block_size) {
+            let data: Vec<u8> = self
+                .virtual_disk
+                .read_data_by_block(*block)
+                .map_err(|err| err.in_file(&fcb.name))?;
+            let block_start: usize = i * block_size;
+            let from: usize = offset.max(block_start);
+            let to: usize = end.min(block_start + block_size);
+            buf[from - offset..to - offset].copy_from_slice(&data[from - block_start..to - block_start]);
+        }
+        Ok(count)
+    }
+
+    /// 把data写入文件的offset处，返回写入的字节数
+    /// 写入超过文件末尾时分配新的块，offset和原来的文件末尾之间用0填充
+    /// 数据分成日志区能容纳的若干段，每段在一个事务中写入；后面的段写入失败时返回已经写入的字节数
+    pub fn write_at(&mut self, handle: FileHandle, offset: u64, data: &[u8]) -> FsResult<usize> {
+        info!("Writing {} bytes to handle {} at {}...", data.len(), handle, offset);
+        if data.is_empty() {
+            self.check_access(handle, true)?;
+            return Ok(0);
+        }
+        // 留出一半日志给FAT、校验和以及目录的修改
+        let chunk_size: usize = match self.virtual_disk.journal_capacity() {
+            Some(capacity) => (capacity / 2).max(1) * self.virtual_disk.block_size(),
+            None => data.len(),
+        };
+        let mut written: usize = 0;
+        for chunk in data.chunks(chunk_size) {
+            let chunk_offset: u64 = offset + written as u64;
+            match self.transaction(|disk| disk.write_at_in_transaction(handle, chunk_offset, chunk)) {
+                Ok(count) => written += count,
+                Err(_) if written > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(written)
+    }
+
+    // write_at的实现，在事务中执行
+    fn write_at_in_transaction(&mut self, handle: FileHandle, offset: u64, data: &[u8]) -> FsResult<usize> {
+        let (mut dir, index) = self.check_access(handle, true)?;
+        let fcb: Fcb = dir.files[index].clone();
+        let offset: usize = usize::try_from(offset).map_err(|_| FsError::NoSpace)?;
+        let end: usize = offset.checked_add(data.len()).ok_or(FsError::NoSpace)?;
+        let length: usize = fcb.length.max(end);
+
+        let blocks: Vec<usize> = self
+            .resize_file_chain(fcb.first_block, length)
+            .map_err(|err| err.in_file(&fcb.name))?;
+        self.write_file_blocks(&blocks, fcb.length, offset, data)
+            .map_err(|err| err.in_file(&fcb.name))?;
+        if length != fcb.length {
+            dir.files[index].length = length;
+            self.store_directory(&dir)?;
+        }
+        Ok(data.len())
+    }
+
+    /// 把文件的长度改为length，变短时释放多余的块，变长时分配新的块并用0填充
+    /// 读写位置不变
+    pub fn truncate(&mut self, handle: FileHandle, length: u64) -> FsResult<()> {
+        self.transaction(|disk| disk.truncate_in_transaction(handle, length))
+    }
+
+    // truncate的实现，在事务中执行
+    fn truncate_in_transaction(&mut self, handle: FileHandle, length: u64) -> FsResult<()> {
+        info!("Truncating handle {} to {} bytes...", handle, length);
+        let (mut dir, index) = self.check_access(handle, true)?;
+        let fcb: Fcb = dir.files[index].clone();
+        let length: usize = usize::try_from(length).map_err(|_| FsError::NoSpace)?;
+
+        let blocks: Vec<usize> = self
+            .resize_file_chain(fcb.first_block, length)
+            .map_err(|err| err.in_file(&fcb.name))?;
+        // 变短时把保留下来的最后一块中新的末尾之后的部分清零，保持填充为0
+        let tail: usize = fcb
+            .length
+            .min(blocks.len() * self.virtual_disk.block_size())
+            .saturating_sub(length);
+        self.write_file_blocks(&blocks, fcb.length, length, &vec![0; tail])
+            .map_err(|err| err.in_file(&fcb.name))?;
+        if length != fcb.length {
+            dir.files[index].length = length;
+            self.store_directory(&dir)?;
+        }
+        Ok(())
+    }
+
+    // 把以first_block开头的块链调整为能容纳length字节，返回调整后的所有块
+    // 变长时在链尾接上新分配的块，变短时释放链尾多余的块，首块始终不变
+    fn resize_file_chain(&mut self, first_block: usize, length: usize) -> FsResult<Vec<usize>> {
+        let mut blocks: Vec<usize> = self.get_file_blocks(first_block)?;
+        let blocks_needed: usize = self.calc_blocks_needed(length);
+        if blocks_needed > blocks.len() {
+            let new_blocks: Vec<usize> = self.allocate_free_space_on_fat(blocks_needed - blocks.len())?;
+            // 原来的最后一块接到新分配的第一块上
+            self.virtual_disk
+                .set_fat(blocks[blocks.len() - 1], FatStatus::NextBlock(new_blocks[0]));
+            blocks.extend(new_blocks);
+        } else if blocks_needed < blocks.len() {
+            let freed: Vec<usize> = blocks.split_off(blocks_needed);
+            self.virtual_disk.set_fat(blocks[blocks.len() - 1], FatStatus::EOF);
+            self.release_blocks(&freed);
+        }
+        Ok(blocks)
+    }
+
+    // 把data写入块链blocks中的offset处，old_length是写入前的文件长度
+    // old_length和offset之间的部分用0填充；只有部分被覆盖的原有块先读出再合并
+    fn write_file_blocks(&mut self, blocks: &[usize], old_length: usize, offset: usize, data: &[u8]) -> FsResult<()> {
+        let block_size: usize = self.virtual_disk.block_size();
+        let start: usize = old_length.min(offset);
+        let end: usize = offset + data.len();
+        if start >= end {
+            return Ok(());
+        }
+        // 写入前已经有数据的块数，之后的块是新分配的
+        let used_blocks: usize = old_length.div_ceil(block_size);
+
+        let last: usize = (end - 1) / block_size;
+        for (i, block) in blocks.iter().enumerate().take(last + 1).skip(start / block_size) {
+            let block_start: usize = i * block_size;
+            let block_end: usize = block_start + block_size;
+            let mut buffer: Vec<u8> = if i < used_blocks && (start > block_start || end < block_end) {
+                self.virtual_disk.read_data_by_block(*block)?
+            } else {
+                vec![0; block_size]
+            };
+            // 原来的文件末尾到offset之间填充0
+            let gap_from: usize = start.max(block_start);
+            let gap_to: usize = offset.min(block_end);
+            if gap_from < gap_to {
+                buffer[gap_from - block_start..gap_to - block_start].fill(0);
+            }
+            let from: usize = offset.max(block_start);
+            let to: usize = end.min(block_end);
+            if from < to {
+                buffer[from - block_start..to - block_start].copy_from_slice(&data[from - offset..to - offset]);
+            }
+            self.virtual_disk.insert_data_by_block(&buffer, *block)?;
+        }
+        Ok(())
+    }
+
+    /// 移动文件的读写位置，返回新的位置。位置可以超过文件末尾，之后写入时中间用0填充
+    pub fn seek(&mut self, handle: FileHandle, pos: SeekFrom) -> FsResult<u64> {
+        let (dir, index) = self.locate_open_file(handle)?;
+        let fcb: &Fcb = &dir.files[index];
+        let position: u64 = self.get_open_file(handle)?.position;
+        let new_position: u64 = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => (fcb.length as u64).checked_add_signed(delta),
+            SeekFrom::Current(delta) => position.checked_add_signed(delta),
+        }
+        .ok_or_else(|| FsError::InvalidSeek(fcb.name.clone()))?;
+        if let Some(file) = self.open_files.get_mut(&handle.0) {
+            file.position = new_position;
+        }
+        Ok(new_position)
+    }
+
+    /// 从当前读写位置读取数据到buf中，并把读写位置向后移动读取的字节数
+    pub fn read(&mut self, handle: FileHandle, buf: &mut [u8]) -> FsResult<usize> {
+        let position: u64 = self.get_open_file(handle)?.position;
+        let count: usize = self.read_at(handle, position, buf)?;
+        if let Some(file) = self.open_files.get_mut(&handle.0) {
+            file.position = position + count as u64;
+        }
+        Ok(count)
+    }
+
+    /// 在当前读写位置写入数据，并把读写位置向后移动写入的字节数
+    /// 以追加方式打开的文件每次都写在文件末尾
+    pub fn write(&mut self, handle: FileHandle, data: &[u8]) -> FsResult<usize> {
+        let file: &OpenFile = self.get_open_file(handle)?;
+        let position: u64 = match file.flags.append {
+            true => self.file_length(handle)?,
+            false => file.position,
+        };
+        let count: usize = self.write_at(handle, position, data)?;
+        if let Some(file) = self.open_files.get_mut(&handle.0) {
+            file.position = position + count as u64;
+        }
+        Ok(count)
+    }
+
+    /// 复制文件，raw_name和new_name都可以是路径
+    pub fn copy_file_by_name(&mut self, raw_name: &str, new_name: &str) -> FsResult<()> {
+        let (_dir, fcb) = self.resolve_fcb(raw_name)?;
+        let data: Vec<u8> = self.get_file_by_fcb(&fcb)?;
+        self.create_file_with_data(new_name, &data)
+    }
+
+    /// 复制文件或目录，目录中的所有文件和子目录都会被复制
+    pub fn copy_file_by_name_recursively(&mut self, raw_name: &str, new_name: &str) -> FsResult<()> {
+        self.transaction(|disk| disk.copy_file_by_name_recursively_in_transaction(raw_name, new_name))
+    }
+
+    // copy_file_by_name_recursively的实现，在事务中执行
+    fn copy_file_by_name_recursively_in_transaction(&mut self, raw_name: &str, new_name: &str) -> FsResult<()> {
+        let (_dir, fcb) = self.resolve_fcb(raw_name)?;
+        let (mut parent, name) = self.resolve_parent(new_name)?;
+        Directory::check_name(&name)?;
+        if parent.get_fcb_by_name(&name).is_some() {
+            return Err(FsError::AlreadyExists(name));
+        }
+
+        let new_fcb: Fcb = self.copy_tree_by_fcb(&fcb, &name, parent.files[1].first_block)?;
+        parent.files.push(new_fcb);
+        self.store_directory(&parent)
     }
 
-    // 复制文件
-    pub fn copy_file_by_name(&mut self, raw_name: &str, new_name: &str) -> bool {
-        let (_, fcb) = self.cur_directory.get_fcb_by_name(raw_name).unwrap();
-        let data: Vec<u8> = self.get_file_by_fcb(fcb);
-        self.create_file_with_data(new_name, &data);
-        true
+    // 复制fcb以及（fcb为目录时）其下所有文件和子目录，返回副本的FCB
+    // 复制失败时释放已经复制的部分
+    fn copy_tree_by_fcb(&mut self, fcb: &Fcb, name: &str, parent_block: usize) -> FsResult<Fcb> {
+        match fcb.file_type {
+            FileType::File => {
+                let data: Vec<u8> = self.get_file_by_fcb(fcb)?;
+                let first_block: usize = self.write_data_to_disk(&data)?;
+                Ok(Fcb {
+                    name: String::from(name),
+                    first_block,
+                    ..fcb.clone()
+                })
+            }
+            FileType::Directory => {
+                let src_dir: Directory = self.get_directory_by_fcb(fcb)?;
+                let first_block: usize = self.write_empty_directory_to_disk(name, parent_block)?;
+                let new_fcb: Fcb = Fcb {
+                    name: String::from(name),
+                    first_block,
+                    ..fcb.clone()
+                };
+                let mut new_dir: Directory = self.get_directory_by_fcb(&new_fcb)?;
+
+                for child in &src_dir.files[2..] {
+                    match self.copy_tree_by_fcb(child, &child.name, first_block) {
+                        Ok(child_fcb) => new_dir.files.push(child_fcb),
+                        Err(err) => {
+                            for copied in &new_dir.files[2..] {
+                                self.delete_tree_by_fcb(copied)?;
+                            }
+                            self.delete_space_on_fat(first_block)?;
+                            return Err(err);
+                        }
+                    }
+                }
+                self.save_directory_to_disk(&new_dir)?;
+
+                Ok(new_fcb)
+            }
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FileType {
     File,
     Directory,
 }
+impl FileType {
+    /// 是否是目录
+    pub fn is_dir(&self) -> bool {
+        *self == FileType::Directory
+    }
+
+    /// 是否是文件
+    pub fn is_file(&self) -> bool {
+        *self == FileType::File
+    }
+}
+
 impl fmt::Display for FileType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -473,6 +1873,7 @@ impl fmt::Display for FileType {
     }
 }
 
+/// 文件控制块，即目录中的一项
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Fcb {
     name: String,         // 文件名
@@ -480,7 +1881,29 @@ pub struct Fcb {
     first_block: usize,   // 起始块号
     length: usize,        // 文件大小
 }
+impl Fcb {
+    /// 文件名
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 文件类型
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// 起始块号
+    pub fn first_block(&self) -> usize {
+        self.first_block
+    }
+
+    /// 文件大小，单位为字节
+    pub fn length(&self) -> usize {
+        self.length
+    }
+}
 
+/// 目录的数据，存储在目录的首块开始的块中
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Directory {
     name: String,
@@ -494,8 +1917,18 @@ impl Directory {
         }
     }
 
-    // 通过文件名获取文件在files中的索引和文件FCB
-    fn get_fcb_by_name(&self, name: &str) -> Option<(usize, &Fcb)> {
+    /// 目录名
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 目录项，前两项固定为".."和"."
+    pub fn files(&self) -> &[Fcb] {
+        &self.files
+    }
+
+    /// 通过文件名获取文件在files中的索引和文件FCB
+    pub fn get_fcb_by_name(&self, name: &str) -> Option<(usize, &Fcb)> {
         let mut res: Option<(usize, &Fcb)> = None;
         for i in 0..self.files.len() {
             if self.files[i].name.as_str() == name {
@@ -507,6 +1940,14 @@ impl Directory {
         res
     }
 
+    // 检查文件名是否合法，"."和".."以及包含'/'的名字不能作为普通文件名
+    fn check_name(name: &str) -> FsResult<()> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FsError::InvalidName(String::from(name)));
+        }
+        Ok(())
+    }
+
     // 通过文件名获取文件在files中的索引
     fn get_index_by_name(&self, name: &str) -> Option<usize> {
         let mut res: Option<usize> = None;
@@ -537,11 +1978,3 @@ impl fmt::Display for Directory {
         fmt::Result::Ok(())
     }
 }
-
-pub fn print_debug_info() {
-    print!("{}", "[DEBUG]\t".fg(ansi_rgb::magenta()));
-}
-
-pub fn print_info() {
-    print!("{}", "[INFO]\t".fg(ansi_rgb::cyan_blue()));
-}
\ No newline at end of file
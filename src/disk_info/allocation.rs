@@ -0,0 +1,228 @@
+use std::fmt;
+use std::str::FromStr;
+use super::free_space::FreeBitmap;
+use super::virtual_disk::FatStatus;
+
+/// 分配空闲块的策略
+///
+/// 除Contiguous外，空间不够一段连续的空闲块时都会继续从其他空闲段分配，文件因此可能不连续
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocationPolicy {
+    /// 首次适应：按块号从小到大使用空闲块
+    #[default]
+    FirstFit,
+    /// 循环首次适应：从上次分配结束的位置开始按块号顺序使用空闲块，到末尾后回到开头
+    NextFit,
+    /// 最佳适应：使用放得下剩余部分的最小空闲段，都放不下时使用最大的空闲段
+    BestFit,
+    /// 最坏适应：总是使用最大的空闲段
+    WorstFit,
+    /// 连续优先：使用块号最小的能放下整个文件的空闲段，都放不下时按首次适应分配
+    Contiguous,
+}
+
+impl AllocationPolicy {
+    /// 所有策略
+    pub const ALL: [AllocationPolicy; 5] = [
+        AllocationPolicy::FirstFit,
+        AllocationPolicy::NextFit,
+        AllocationPolicy::BestFit,
+        AllocationPolicy::WorstFit,
+        AllocationPolicy::Contiguous,
+    ];
+
+    /// 按策略选出blocks_needed个空闲块，返回的顺序就是块链的顺序
+    /// cursor是上次分配结束的位置，空闲块不够时返回None
+    pub fn choose_blocks(&self, free_space: &FreeBitmap, cursor: usize, blocks_needed: usize) -> Option<Vec<usize>> {
+        if blocks_needed > free_space.free_count() {
+            return None;
+        }
+        let mut blocks: Vec<usize> = Vec::with_capacity(blocks_needed);
+        match self {
+            AllocationPolicy::FirstFit => {
+                // 逐块查找，不需要先找出所有空闲段
+                let mut block: usize = 0;
+                while blocks.len() < blocks_needed {
+                    block = free_space.next_free(block)?;
+                    blocks.push(block);
+                    block += 1;
+                }
+            }
+            AllocationPolicy::NextFit => {
+                // 从cursor开始，到末尾后回到开头
+                let mut runs: Vec<(usize, usize)> = free_space.free_runs();
+                let split: usize = runs.partition_point(|(start, len)| start + len <= cursor);
+                let cut: Option<(usize, usize)> = runs.get(split).copied().filter(|(start, _)| *start < cursor);
+                runs.rotate_left(split);
+                if let Some((start, len)) = cut {
+                    // cursor在这一段中间，后一半先用，前一半最后用
+                    runs[0] = (cursor, start + len - cursor);
+                    runs.push((start, cursor - start));
+                }
+                take_runs(&mut blocks, runs, blocks_needed);
+            }
+            AllocationPolicy::BestFit => {
+                // 按长度从大到小排列，放不下时总是用掉最前面最大的一段
+                let mut runs: Vec<(usize, usize)> = free_space.free_runs();
+                runs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                let mut first: usize = 0;
+                while blocks.len() < blocks_needed {
+                    let rest: usize = blocks_needed - blocks.len();
+                    // 剩下的段中放得下的最小一段
+                    let fit: usize = first + runs[first..].partition_point(|(_, len)| *len >= rest);
+                    if fit > first {
+                        take_runs(&mut blocks, vec![runs[fit - 1]], blocks_needed);
+                    } else {
+                        take_runs(&mut blocks, vec![runs[first]], blocks_needed);
+                        first += 1;
+                    }
+                }
+            }
+            AllocationPolicy::WorstFit => {
+                let mut runs: Vec<(usize, usize)> = free_space.free_runs();
+                runs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                take_runs(&mut blocks, runs, blocks_needed);
+            }
+            AllocationPolicy::Contiguous => {
+                let runs: Vec<(usize, usize)> = free_space.free_runs();
+                match runs.iter().find(|(_, len)| *len >= blocks_needed) {
+                    Some(run) => take_runs(&mut blocks, vec![*run], blocks_needed),
+                    None => take_runs(&mut blocks, runs, blocks_needed),
+                }
+            }
+        }
+        Some(blocks)
+    }
+}
+
+// 依次从各个空闲段的开头取块，直到取够blocks_needed块
+fn take_runs(blocks: &mut Vec<usize>, runs: Vec<(usize, usize)>, blocks_needed: usize) {
+    for (start, len) in runs {
+        let rest: usize = blocks_needed - blocks.len();
+        if rest == 0 {
+            break;
+        }
+        blocks.extend(start..start + len.min(rest));
+    }
+}
+
+impl fmt::Display for AllocationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name: &str = match self {
+            AllocationPolicy::FirstFit => "first-fit",
+            AllocationPolicy::NextFit => "next-fit",
+            AllocationPolicy::BestFit => "best-fit",
+            AllocationPolicy::WorstFit => "worst-fit",
+            AllocationPolicy::Contiguous => "contiguous",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for AllocationPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<AllocationPolicy, String> {
+        AllocationPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.to_string() == name)
+            .ok_or_else(|| format!("unknown allocation policy '{}'", name))
+    }
+}
+
+/// 碎片统计，用于比较不同分配策略的效果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FragmentationStats {
+    /// 统计时使用的分配策略
+    pub policy: AllocationPolicy,
+    /// 总块数
+    pub total_blocks: usize,
+    /// 空闲块数
+    pub free_blocks: usize,
+    /// 连续空闲段的数量
+    pub free_runs: usize,
+    /// 最长的连续空闲段
+    pub largest_free_run: usize,
+    /// 块链（文件和目录）的数量
+    pub chains: usize,
+    /// 不连续的块链的数量
+    pub fragmented_chains: usize,
+    /// 所有块链的连续段数之和
+    pub extents: usize,
+}
+
+impl FragmentationStats {
+    /// 根据FAT和空闲块位图统计
+    pub fn from_fat(policy: AllocationPolicy, fat: &[FatStatus], free_space: &FreeBitmap) -> FragmentationStats {
+        let runs: Vec<(usize, usize)> = free_space.free_runs();
+        let mut stats: FragmentationStats = FragmentationStats {
+            policy,
+            total_blocks: fat.len(),
+            free_blocks: free_space.free_count(),
+            free_runs: runs.len(),
+            largest_free_run: runs.iter().map(|(_, len)| *len).max().unwrap_or(0),
+            chains: 0,
+            fragmented_chains: 0,
+            extents: 0,
+        };
+
+        // 没有被其他块指向的已使用块是块链的首块
+        let mut is_head: Vec<bool> = fat.iter().map(|fat_item| !matches!(fat_item, FatStatus::UnUsed)).collect();
+        for fat_item in fat {
+            if let FatStatus::NextBlock(next) = fat_item {
+                if let Some(head) = is_head.get_mut(*next) {
+                    *head = false;
+                }
+            }
+        }
+        for head in (0..fat.len()).filter(|block| is_head[*block]) {
+            let mut extents: usize = 1;
+            let mut block: usize = head;
+            // 最多走过所有块，FAT损坏成环时也能结束
+            for _ in 0..fat.len() {
+                match fat[block] {
+                    FatStatus::NextBlock(next) if next < fat.len() => {
+                        if next != block + 1 {
+                            extents += 1;
+                        }
+                        block = next;
+                    }
+                    _ => break,
+                }
+            }
+            stats.chains += 1;
+            stats.extents += extents;
+            if extents > 1 {
+                stats.fragmented_chains += 1;
+            }
+        }
+        stats
+    }
+
+    /// 外部碎片率：不在最长空闲段中的空闲块所占的比例
+    pub fn external_fragmentation(&self) -> f64 {
+        if self.free_blocks == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free_run as f64 / self.free_blocks as f64
+    }
+
+    /// 平均每条块链的连续段数，1表示所有文件都是连续的
+    pub fn average_extents(&self) -> f64 {
+        if self.chains == 0 {
+            return 1.0;
+        }
+        self.extents as f64 / self.chains as f64
+    }
+}
+
+impl fmt::Display for FragmentationStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "allocation policy: {}", self.policy)?;
+        writeln!(f, "free blocks: {} / {}", self.free_blocks, self.total_blocks)?;
+        writeln!(f, "free runs: {} (largest: {} blocks)", self.free_runs, self.largest_free_run)?;
+        writeln!(f, "external fragmentation: {:.1}%", self.external_fragmentation() * 100.0)?;
+        writeln!(f, "fragmented chains: {} / {}", self.fragmented_chains, self.chains)?;
+        write!(f, "average extents per chain: {:.2}", self.average_extents())
+    }
+}
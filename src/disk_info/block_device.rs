@@ -0,0 +1,221 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use memmap2::MmapMut;
+use super::error::{FsError, FsResult};
+
+/// 块设备：按块读写的存储，虚拟磁盘的超级块、FAT区和数据区都保存在块设备上
+pub trait BlockDevice {
+    /// 读出一整块
+    fn read_block(&self, block: usize) -> FsResult<Vec<u8>>;
+    /// 从块的开头写入数据，数据长度不能超过块大小
+    fn write_block(&mut self, block: usize, data: &[u8]) -> FsResult<()>;
+    /// 块数量
+    fn block_count(&self) -> usize;
+    /// 块大小
+    fn block_size(&self) -> usize;
+    /// 把缓存中的数据写到底层存储上
+    fn flush(&mut self) -> FsResult<()>;
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Box<D> {
+    fn read_block(&self, block: usize) -> FsResult<Vec<u8>> {
+        (**self).read_block(block)
+    }
+
+    fn write_block(&mut self, block: usize, data: &[u8]) -> FsResult<()> {
+        (**self).write_block(block, data)
+    }
+
+    fn block_count(&self) -> usize {
+        (**self).block_count()
+    }
+
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn flush(&mut self) -> FsResult<()> {
+        (**self).flush()
+    }
+}
+
+// 检查块号和写入数据的长度
+fn check_block_range(device: &dyn BlockDevice, block: usize, len: usize) -> FsResult<()> {
+    if block >= device.block_count() || len > device.block_size() {
+        return Err(FsError::CorruptChain(block));
+    }
+    Ok(())
+}
+
+/// 内存块设备：所有数据都放在内存中
+pub struct MemoryDevice {
+    data: Vec<u8>,
+    block_size: usize,
+}
+
+impl MemoryDevice {
+    /// 创建全部为0的内存块设备
+    pub fn new(block_size: usize, block_count: usize) -> MemoryDevice {
+        MemoryDevice {
+            data: vec![0u8; block_size * block_count],
+            block_size,
+        }
+    }
+
+    /// 用已有的数据创建内存块设备，不足一块的部分用0填充
+    pub fn from_bytes(mut data: Vec<u8>, block_size: usize) -> MemoryDevice {
+        data.resize(data.len().div_ceil(block_size) * block_size, 0);
+        MemoryDevice { data, block_size }
+    }
+
+    /// 取出全部数据
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl BlockDevice for MemoryDevice {
+    fn read_block(&self, block: usize) -> FsResult<Vec<u8>> {
+        check_block_range(self, block, 0)?;
+        Ok(self.data[block * self.block_size..(block + 1) * self.block_size].to_vec())
+    }
+
+    fn write_block(&mut self, block: usize, data: &[u8]) -> FsResult<()> {
+        check_block_range(self, block, data.len())?;
+        let offset: usize = block * self.block_size;
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn block_count(&self) -> usize {
+        self.data.len() / self.block_size
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn flush(&mut self) -> FsResult<()> {
+        Ok(())
+    }
+}
+
+/// 文件块设备：按块直接读写宿主机上的文件
+pub struct FileDevice {
+    file: File,
+    block_size: usize,
+    block_count: usize,
+}
+
+impl FileDevice {
+    /// 创建新文件作为块设备，已有的文件会被清空
+    pub fn create(path: &str, block_size: usize, block_count: usize) -> FsResult<FileDevice> {
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        // 不需要真正写入数据，文件系统会在文件中留下空洞
+        file.set_len((block_size * block_count) as u64)?;
+        Ok(FileDevice { file, block_size, block_count })
+    }
+
+    /// 打开已有的文件作为块设备，块数量由文件长度决定
+    pub fn open(path: &str, block_size: usize) -> FsResult<FileDevice> {
+        let file: File = OpenOptions::new().read(true).write(true).open(path)?;
+        let block_count: usize = file.metadata()?.len() as usize / block_size;
+        Ok(FileDevice { file, block_size, block_count })
+    }
+}
+
+impl BlockDevice for FileDevice {
+    fn read_block(&self, block: usize) -> FsResult<Vec<u8>> {
+        check_block_range(self, block, 0)?;
+        let mut buffer: Vec<u8> = vec![0u8; self.block_size];
+        // &File同样实现了Read和Seek，读取时不需要可变借用
+        let mut file: &File = &self.file;
+        file.seek(SeekFrom::Start((block * self.block_size) as u64))?;
+        file.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn write_block(&mut self, block: usize, data: &[u8]) -> FsResult<()> {
+        check_block_range(self, block, data.len())?;
+        self.file.seek(SeekFrom::Start((block * self.block_size) as u64))?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    fn block_count(&self) -> usize {
+        self.block_count
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn flush(&mut self) -> FsResult<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// 内存映射块设备：把宿主机上的文件映射到内存中读写，由操作系统负责换入换出
+pub struct MmapDevice {
+    mmap: MmapMut,
+    block_size: usize,
+}
+
+impl MmapDevice {
+    /// 创建新文件并映射为块设备，已有的文件会被清空
+    pub fn create(path: &str, block_size: usize, block_count: usize) -> FsResult<MmapDevice> {
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len((block_size * block_count) as u64)?;
+        MmapDevice::map(&file, block_size)
+    }
+
+    /// 打开已有的文件并映射为块设备，块数量由文件长度决定
+    pub fn open(path: &str, block_size: usize) -> FsResult<MmapDevice> {
+        let file: File = OpenOptions::new().read(true).write(true).open(path)?;
+        MmapDevice::map(&file, block_size)
+    }
+
+    fn map(file: &File, block_size: usize) -> FsResult<MmapDevice> {
+        // 映射期间文件不能被其他程序截断，否则访问映射的内存会出错
+        let mmap: MmapMut = unsafe { MmapMut::map_mut(file)? };
+        Ok(MmapDevice { mmap, block_size })
+    }
+}
+
+impl BlockDevice for MmapDevice {
+    fn read_block(&self, block: usize) -> FsResult<Vec<u8>> {
+        check_block_range(self, block, 0)?;
+        Ok(self.mmap[block * self.block_size..(block + 1) * self.block_size].to_vec())
+    }
+
+    fn write_block(&mut self, block: usize, data: &[u8]) -> FsResult<()> {
+        check_block_range(self, block, data.len())?;
+        let offset: usize = block * self.block_size;
+        self.mmap[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn block_count(&self) -> usize {
+        self.mmap.len() / self.block_size
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn flush(&mut self) -> FsResult<()> {
+        self.mmap.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use ansi_rgb::{Foreground, WithForeground};
+use super::allocation::FragmentationStats;
+use super::FileType;
+
+// 每行显示的块数
+const BLOCKS_PER_ROW: usize = 64;
+
+/// 一个块的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockState {
+    /// 空闲块
+    Free,
+    /// 属于files中的第几个文件，不是文件的最后一块
+    Used(usize),
+    /// 属于files中的第几个文件，是文件的最后一块
+    Eof(usize),
+    /// 已被使用，但不属于任何文件
+    Orphan,
+}
+
+/// 一个文件或目录占用的块
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileFragments {
+    /// 绝对路径
+    pub path: String,
+    pub file_type: FileType,
+    pub first_block: usize,
+    /// 占用的块数
+    pub blocks: usize,
+    /// 连续段数，1表示文件是连续存放的
+    pub fragments: usize,
+}
+
+/// 磁盘块分布图：每一块的状态、每个文件的碎片数和整体的碎片统计
+#[derive(Debug, Clone)]
+pub struct BlockMap {
+    pub blocks: Vec<BlockState>,
+    pub files: Vec<FileFragments>,
+    pub stats: FragmentationStats,
+}
+
+impl BlockMap {
+    /// 不连续存放的文件所占的比例
+    pub fn file_fragmentation(&self) -> f64 {
+        if self.files.is_empty() {
+            return 0.0;
+        }
+        let fragmented: usize = self.files.iter().filter(|file| file.fragments > 1).count();
+        fragmented as f64 / self.files.len() as f64
+    }
+
+    /// 碎片情况的摘要
+    pub fn summary(&self) -> String {
+        let fragmented: usize = self.files.iter().filter(|file| file.fragments > 1).count();
+        format!(
+            "largest free run: {} blocks ({} free runs)\nfragmented files: {} / {} ({:.1}%)\nexternal fragmentation: {:.1}%",
+            self.stats.largest_free_run,
+            self.stats.free_runs,
+            fragmented,
+            self.files.len(),
+            self.file_fragmentation() * 100.0,
+            self.stats.external_fragmentation() * 100.0
+        )
+    }
+}
+
+// 文件在分布图中的标记，超过26个文件后循环使用
+fn file_label(index: usize, eof: bool) -> char {
+    let label: char = (b'a' + (index % 26) as u8) as char;
+    if eof {
+        label.to_ascii_uppercase()
+    } else {
+        label
+    }
+}
+
+// 文件在分布图中的颜色，相邻的文件颜色不同
+fn file_color<T>(index: usize, text: T) -> WithForeground<T> {
+    match index % 8 {
+        0 => text.fg(ansi_rgb::cyan()),
+        1 => text.fg(ansi_rgb::yellow()),
+        2 => text.fg(ansi_rgb::green()),
+        3 => text.fg(ansi_rgb::magenta()),
+        4 => text.fg(ansi_rgb::orange()),
+        5 => text.fg(ansi_rgb::blue()),
+        6 => text.fg(ansi_rgb::yellow_green()),
+        _ => text.fg(ansi_rgb::magenta_pink()),
+    }
+}
+
+impl fmt::Display for BlockMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Block map: '.' free, lower case used, upper case last block of a file, '?' used but not referenced by any file"
+        )?;
+        let width: usize = self.blocks.len().to_string().len();
+        for (row, blocks) in self.blocks.chunks(BLOCKS_PER_ROW).enumerate() {
+            write!(f, "{:>width$}  ", row * BLOCKS_PER_ROW, width = width)?;
+            for block in blocks {
+                match block {
+                    BlockState::Free => write!(f, ".")?,
+                    BlockState::Used(index) => write!(f, "{}", file_color(*index, file_label(*index, false)))?,
+                    BlockState::Eof(index) => write!(f, "{}", file_color(*index, file_label(*index, true)))?,
+                    BlockState::Orphan => write!(f, "{}", '?'.fg(ansi_rgb::red()))?,
+                }
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f, "\nFiles:")?;
+        for (index, file) in self.files.iter().enumerate() {
+            writeln!(
+                f,
+                "  {}  {}\t{}\tfirst block: {}\tblocks: {}\tfragments: {}",
+                file_color(index, file_label(index, false)),
+                file.path,
+                file.file_type,
+                file.first_block,
+                file.blocks,
+                file.fragments
+            )?;
+        }
+        write!(f, "\n{}", self.summary())
+    }
+}
@@ -0,0 +1,47 @@
+use std::fmt;
+
+/// 碎片整理的结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DefragReport {
+    /// 移动了数据的块数
+    pub blocks_moved: usize,
+    /// 被移动过的文件和目录数
+    pub files_moved: usize,
+    /// 检查过的文件和目录数
+    pub files_checked: usize,
+    /// 因为要移动的块超过日志区的容量而没有移动的文件和目录数
+    pub files_skipped: usize,
+    /// 文件和目录总数
+    pub files_total: usize,
+    /// 是否整理完所有文件，中途停止时为false
+    pub completed: bool,
+}
+
+impl fmt::Display for DefragReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} blocks moved, {} of {} files and directories moved ({} checked)",
+            if self.completed { "defragmentation finished" } else { "defragmentation stopped" },
+            self.blocks_moved,
+            self.files_moved,
+            self.files_total,
+            self.files_checked
+        )?;
+        if self.files_skipped > 0 {
+            write!(f, ", {} skipped because they do not fit in the journal", self.files_skipped)?;
+        }
+        Ok(())
+    }
+}
+
+// 目录树中的一个文件或目录，整理时用来找到需要修改的目录项
+#[derive(Debug, Clone)]
+pub(crate) struct TreeNode {
+    pub first_block: usize,
+    pub is_directory: bool,
+    // 父目录在节点列表中的下标，根目录没有父目录
+    pub parent: Option<usize>,
+    // 子目录在节点列表中的下标
+    pub child_directories: Vec<usize>,
+}
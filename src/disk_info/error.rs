@@ -0,0 +1,99 @@
+use std::{fmt, io};
+
+/// 文件系统操作中可能出现的错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsError {
+    NotFound(String),           // 文件或目录不存在
+    AlreadyExists(String),      // 已存在同名文件或目录
+    NotADirectory(String),      // 需要目录，但给出的是文件
+    IsADirectory(String),       // 需要文件，但给出的是目录
+    DirectoryNotEmpty(String),  // 目录非空
+    InvalidName(String),        // 非法的文件名（如 "." 和 ".."）
+    InUse(String),              // 目录正在使用（如删除当前目录）
+    IntoItself(String),         // 不能把目录移动到自己的子目录中
+    BadHandle(usize),           // 文件句柄不存在或已经关闭
+    AccessDenied(String),       // 文件打开时没有允许这种读写
+    InvalidSeek(String),        // 读写位置移动到了文件开头之前
+    NoSpace,                    // 磁盘没有足够的空闲块
+    CorruptChain(usize),        // FAT链或块中的数据损坏，参数为出错的块号
+    BadChecksum(String, usize), // 块中的数据和校验和不一致，参数为文件名（未知时为空）和块号
+    TransactionTooLarge(usize), // 一次操作修改的块超过了日志区的容量，参数为修改的块数
+    InvalidImage(String),       // 镜像文件无法解析，参数为原因
+    InvalidGeometry(String),    // 不支持的磁盘参数
+    Io(io::ErrorKind, String),  // 读写镜像文件出错，参数为io::Error的类型和信息
+}
+
+pub type FsResult<T> = Result<T, FsError>;
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound(name) => write!(f, "'{}': No such file or directory", name),
+            FsError::AlreadyExists(name) => write!(f, "'{}': File exists", name),
+            FsError::NotADirectory(name) => write!(f, "'{}': Not a directory", name),
+            FsError::IsADirectory(name) => write!(f, "'{}': Is a directory", name),
+            FsError::DirectoryNotEmpty(name) => write!(f, "'{}': Directory not empty", name),
+            FsError::InvalidName(name) => write!(f, "'{}': Invalid file name", name),
+            FsError::InUse(name) => write!(f, "'{}': Directory is in use", name),
+            FsError::IntoItself(name) => write!(f, "'{}': Cannot move a directory into itself", name),
+            FsError::BadHandle(handle) => write!(f, "#{}: Bad file handle", handle),
+            FsError::AccessDenied(name) => write!(f, "'{}': File is not open for this access", name),
+            FsError::InvalidSeek(name) => write!(f, "'{}': Cannot seek before the start of the file", name),
+            FsError::NoSpace => write!(f, "No space left on disk"),
+            FsError::CorruptChain(block) => write!(f, "Bad block detected at {}", block),
+            FsError::BadChecksum(name, block) if name.is_empty() => {
+                write!(f, "Checksum mismatch in block {}", block)
+            }
+            FsError::BadChecksum(name, block) => write!(f, "'{}': Checksum mismatch in block {}", name, block),
+            FsError::TransactionTooLarge(blocks) => {
+                write!(f, "Operation changes {} blocks, more than the journal can hold", blocks)
+            }
+            FsError::InvalidImage(reason) => write!(f, "Not a valid file system image: {}", reason),
+            FsError::InvalidGeometry(reason) => write!(f, "Invalid disk geometry: {}", reason),
+            FsError::Io(_, msg) => write!(f, "I/O error: {}", msg),
+        }
+    }
+}
+
+impl FsError {
+    // 给校验和错误加上出错的文件名，已经有文件名的和其他错误原样返回
+    pub(crate) fn in_file(self, name: &str) -> FsError {
+        match self {
+            FsError::BadChecksum(file, block) if file.is_empty() => FsError::BadChecksum(String::from(name), block),
+            err => err,
+        }
+    }
+}
+
+impl std::error::Error for FsError {}
+
+impl From<io::Error> for FsError {
+    fn from(err: io::Error) -> FsError {
+        FsError::Io(err.kind(), err.to_string())
+    }
+}
+
+// 转换为std::io的错误，原来的FsError作为内部错误保留，可以通过io::Error::get_ref取回
+impl From<FsError> for io::Error {
+    fn from(err: FsError) -> io::Error {
+        let kind: io::ErrorKind = match err {
+            FsError::NotFound(_) => io::ErrorKind::NotFound,
+            FsError::AlreadyExists(_) => io::ErrorKind::AlreadyExists,
+            FsError::NotADirectory(_) => io::ErrorKind::NotADirectory,
+            FsError::IsADirectory(_) => io::ErrorKind::IsADirectory,
+            FsError::DirectoryNotEmpty(_) => io::ErrorKind::DirectoryNotEmpty,
+            FsError::InvalidName(_) => io::ErrorKind::InvalidFilename,
+            FsError::InUse(_) => io::ErrorKind::ResourceBusy,
+            FsError::IntoItself(_) | FsError::BadHandle(_) | FsError::InvalidSeek(_) => io::ErrorKind::InvalidInput,
+            FsError::AccessDenied(_) => io::ErrorKind::PermissionDenied,
+            FsError::NoSpace => io::ErrorKind::StorageFull,
+            FsError::CorruptChain(_) | FsError::BadChecksum(..) | FsError::InvalidImage(_) => {
+                io::ErrorKind::InvalidData
+            }
+            FsError::TransactionTooLarge(_) => io::ErrorKind::FileTooLarge,
+            FsError::InvalidGeometry(_) => io::ErrorKind::InvalidInput,
+            FsError::Io(kind, _) => kind,
+        };
+        io::Error::new(kind, err)
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt;
+
+/// 打开文件时的选项
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenFlags {
+    /// 可以读取
+    pub read: bool,
+    /// 可以写入
+    pub write: bool,
+    /// 文件不存在时创建空文件
+    pub create: bool,
+    /// 打开时把文件长度截断为0，需要可以写入
+    pub truncate: bool,
+    /// 每次写入前都移动到文件末尾，需要可以写入
+    pub append: bool,
+}
+
+impl OpenFlags {
+    /// 只读
+    pub const READ_ONLY: OpenFlags = OpenFlags {
+        read: true,
+        write: false,
+        create: false,
+        truncate: false,
+        append: false,
+    };
+    /// 只写
+    pub const WRITE_ONLY: OpenFlags = OpenFlags {
+        read: false,
+        write: true,
+        ..OpenFlags::READ_ONLY
+    };
+    /// 读写
+    pub const READ_WRITE: OpenFlags = OpenFlags {
+        read: true,
+        ..OpenFlags::WRITE_ONLY
+    };
+
+    /// 文件不存在时创建空文件
+    pub fn with_create(self) -> OpenFlags {
+        OpenFlags { create: true, ..self }
+    }
+
+    /// 打开时把文件长度截断为0
+    pub fn with_truncate(self) -> OpenFlags {
+        OpenFlags { truncate: true, ..self }
+    }
+
+    /// 每次写入前都移动到文件末尾
+    pub fn with_append(self) -> OpenFlags {
+        OpenFlags { append: true, ..self }
+    }
+}
+
+/// 打开的文件的句柄，由DiskInfo::open_file返回，close之后不能再使用
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileHandle(pub(crate) usize);
+
+impl fmt::Display for FileHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+// 打开的文件：通过父目录的首块和文件的首块找到它的FCB，文件被改名、移动或碎片整理后同步修改
+#[derive(Debug, Clone)]
+pub(crate) struct OpenFile {
+    pub parent_block: usize,
+    pub first_block: usize,
+    pub flags: OpenFlags,
+    // 当前读写位置
+    pub position: u64,
+}
@@ -0,0 +1,153 @@
+use super::virtual_disk::FatStatus;
+
+// 每个字记录64块的空闲情况，每个摘要位对应一个字
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// 空闲块位图：和FAT保持同步，查找空闲块时不需要扫描整个FAT
+///
+/// 位为1表示对应的块空闲。摘要位图记录每个字中是否还有空闲块，
+/// 查找时先在摘要中跳过已经占满的字，再在字中找到最低的空闲位，
+/// 所以分配和释放N个块大约只需要O(N)的时间，空闲块总是从块号最小的开始分配
+#[derive(Debug, Clone)]
+pub struct FreeBitmap {
+    words: Vec<u64>,
+    summary: Vec<u64>,
+    len: usize,
+    free_count: usize,
+    // 摘要中在hint之前的字都已经占满
+    hint: usize,
+}
+
+impl FreeBitmap {
+    /// 根据FAT创建位图
+    pub fn from_fat(fat: &[FatStatus]) -> FreeBitmap {
+        let word_count: usize = fat.len().div_ceil(WORD_BITS);
+        let mut bitmap: FreeBitmap = FreeBitmap {
+            words: vec![0u64; word_count],
+            summary: vec![0u64; word_count.div_ceil(WORD_BITS)],
+            len: fat.len(),
+            free_count: 0,
+            hint: 0,
+        };
+        for (block, fat_item) in fat.iter().enumerate() {
+            if let FatStatus::UnUsed = fat_item {
+                bitmap.mark_free(block);
+            }
+        }
+        bitmap
+    }
+
+    /// 块数量
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// 是否没有任何块
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 空闲块数量
+    pub fn free_count(&self) -> usize {
+        self.free_count
+    }
+
+    /// 块是否空闲
+    pub fn is_free(&self, block: usize) -> bool {
+        self.words[block / WORD_BITS] & (1 << (block % WORD_BITS)) != 0
+    }
+
+    /// 块号最小的空闲块
+    pub fn first_free(&self) -> Option<usize> {
+        self.next_free(self.hint * WORD_BITS * WORD_BITS)
+    }
+
+    /// 块号不小于from的第一个空闲块
+    pub fn next_free(&self, from: usize) -> Option<usize> {
+        if from >= self.len {
+            return None;
+        }
+        // 先在from所在的字中查找
+        let word: usize = from / WORD_BITS;
+        let bits: u64 = self.words[word] & (u64::MAX << (from % WORD_BITS));
+        if bits != 0 {
+            return Some(word * WORD_BITS + bits.trailing_zeros() as usize);
+        }
+        // 再通过摘要查找之后第一个还有空闲块的字
+        let next_word: usize = word + 1;
+        let mut index: usize = next_word / WORD_BITS;
+        let mut summary: u64 = match self.summary.get(index) {
+            Some(summary) => summary & (u64::MAX << (next_word % WORD_BITS)),
+            None => return None,
+        };
+        loop {
+            if summary != 0 {
+                let word: usize = index * WORD_BITS + summary.trailing_zeros() as usize;
+                return Some(word * WORD_BITS + self.words[word].trailing_zeros() as usize);
+            }
+            index += 1;
+            summary = *self.summary.get(index)?;
+        }
+    }
+
+    /// 块号不小于from的第一个已使用的块，没有时返回块数量
+    pub fn next_used(&self, from: usize) -> usize {
+        let mut word: usize = from / WORD_BITS;
+        if word >= self.words.len() {
+            return self.len;
+        }
+        // 取反后位为1表示已使用，最后一个字中超出块数量的位也是1
+        let mut bits: u64 = !self.words[word] & (u64::MAX << (from % WORD_BITS));
+        loop {
+            if bits != 0 {
+                return (word * WORD_BITS + bits.trailing_zeros() as usize).min(self.len);
+            }
+            word += 1;
+            match self.words.get(word) {
+                Some(free) => bits = !free,
+                None => return self.len,
+            }
+        }
+    }
+
+    /// 所有连续的空闲块，按块号从小到大返回每段的首块和长度
+    pub fn free_runs(&self) -> Vec<(usize, usize)> {
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        let mut block: usize = 0;
+        while let Some(start) = self.next_free(block) {
+            let end: usize = self.next_used(start);
+            runs.push((start, end - start));
+            block = end;
+        }
+        runs
+    }
+
+    /// 标记块已被使用
+    pub fn mark_used(&mut self, block: usize) {
+        if !self.is_free(block) {
+            return;
+        }
+        let word: usize = block / WORD_BITS;
+        self.words[word] &= !(1 << (block % WORD_BITS));
+        self.free_count -= 1;
+        if self.words[word] == 0 {
+            // 字已占满，从摘要中去掉
+            self.summary[word / WORD_BITS] &= !(1 << (word % WORD_BITS));
+            while self.hint < self.summary.len() && self.summary[self.hint] == 0 {
+                self.hint += 1;
+            }
+        }
+    }
+
+    /// 标记块空闲
+    pub fn mark_free(&mut self, block: usize) {
+        if self.is_free(block) {
+            return;
+        }
+        let word: usize = block / WORD_BITS;
+        self.words[word] |= 1 << (block % WORD_BITS);
+        self.summary[word / WORD_BITS] |= 1 << (word % WORD_BITS);
+        self.free_count += 1;
+        self.hint = self.hint.min(word / WORD_BITS);
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+
+/// fsck发现的问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// 块链中的下一块超出了磁盘范围
+    OutOfRange { path: String, next: usize },
+    /// 块链中出现了未使用的块
+    UnusedInChain { path: String, block: usize },
+    /// 块链中有环
+    Cycle { path: String, block: usize },
+    /// 块链和另一个文件的块链共用了块
+    SharedChain { path: String, other: String, block: usize },
+    /// FCB记录的长度和块链的长度不一致
+    LengthMismatch { path: String, length: usize, blocks: usize },
+    /// 目录的"."或".."没有指向正确的块
+    BrokenLink { path: String, name: String, expected: usize, found: usize },
+    /// 目录的数据无法读取
+    UnreadableDirectory { path: String },
+    /// 已被使用但不属于任何文件的块
+    LeakedBlocks { blocks: Vec<usize> },
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsckProblem::OutOfRange { path, next } => {
+                write!(f, "{}: chain points at block {} beyond the end of the disk", path, next)
+            }
+            FsckProblem::UnusedInChain { path, block } => write!(f, "{}: chain runs into unused block {}", path, block),
+            FsckProblem::Cycle { path, block } => write!(f, "{}: chain loops back to block {}", path, block),
+            FsckProblem::SharedChain { path, other, block } => {
+                write!(f, "{}: chain shares block {} with {}", path, block, other)
+            }
+            FsckProblem::LengthMismatch { path, length, blocks } => {
+                write!(f, "{}: length {} does not match a chain of {} blocks", path, length, blocks)
+            }
+            FsckProblem::BrokenLink { path, name, expected, found } => {
+                write!(f, "{}: '{}' points at block {} instead of {}", path, name, found, expected)
+            }
+            FsckProblem::UnreadableDirectory { path } => write!(f, "{}: directory data cannot be read", path),
+            FsckProblem::LeakedBlocks { blocks } => {
+                write!(f, "{} blocks are in use but not referenced by any file: {:?}", blocks.len(), blocks)
+            }
+        }
+    }
+}
+
+/// fsck的结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// 检查时发现的问题
+    pub problems: Vec<FsckProblem>,
+    /// 修复了多少个问题，不修复时为0
+    pub repaired: usize,
+    /// 修复后仍然存在的问题，不修复时和problems相同
+    pub remaining: Vec<FsckProblem>,
+}
+
+impl FsckReport {
+    /// 检查结束时文件系统是否一致
+    pub fn is_clean(&self) -> bool {
+        self.remaining.is_empty()
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.problems.is_empty() {
+            return write!(f, "file system is clean");
+        }
+        writeln!(f, "{} problems found:", self.problems.len())?;
+        for problem in &self.problems {
+            writeln!(f, "  {}", problem)?;
+        }
+        if self.repaired > 0 {
+            writeln!(f, "{} problems repaired", self.repaired)?;
+        }
+        if self.remaining.is_empty() {
+            write!(f, "file system is clean")
+        } else {
+            write!(f, "{} problems remain", self.remaining.len())
+        }
+    }
+}
+
+// 检查时发现的问题，以及修复时需要的信息
+#[derive(Debug, Clone)]
+pub(crate) struct FsckFinding {
+    pub problem: FsckProblem,
+    // 问题所在的FCB：父目录的首块和文件名，根目录没有
+    pub location: Option<(usize, String)>,
+    pub is_directory: bool,
+    // 块链中没有问题的部分
+    pub chain: Vec<usize>,
+}
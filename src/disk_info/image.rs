@@ -0,0 +1,431 @@
+// 镜像文件的磁盘布局，所有整数都是定长的小端序，在任何机器上生成的镜像都可以在其他机器上打开
+// 块大小和块数量在创建文件系统时确定（见Geometry），打开镜像时以超级块中记录的为准
+//
+// +--------------------+  0
+// | 超级块             |  占用一个块，其余部分用0填充
+// +--------------------+  fat_offset
+// | FAT区              |  每块一项，每项4字节，按块大小向上取整
+// +--------------------+  checksum_offset
+// | 校验和区           |  每块一项，每项是数据块的CRC32C，4字节，按块大小向上取整
+// +--------------------+  journal_offset
+// | 日志区             |  journal_blocks个块，格式见journal.rs
+// +--------------------+  data_offset
+// | 数据区             |  block_count个块
+// +--------------------+
+//
+// 超级块：
+//   偏移  长度  内容
+//   0     8     魔数 "RSFSIMG\0"
+//   8     4     格式版本
+//   12    4     块大小
+//   16    4     块数量
+//   20    4     根目录所在的块
+//   24    8     FAT区的偏移
+//   32    8     数据区的偏移
+//   40    8     日志区的偏移
+//   48    4     日志区的块数
+//   52    8     校验和区的偏移
+//
+// 版本1的镜像没有日志区，超级块只有前40字节，打开时按日志区为空处理
+// 版本2的镜像没有校验和区，超级块只有前52字节，打开时按校验和区为空处理，不检查数据块的校验和
+//
+// FAT项：0xFFFFFFFF 未使用，0xFFFFFFFE 结束标志，其他值为下一块块号
+// 校验和项：小端序的CRC32C，只有已使用的块的校验和有意义
+//
+// 目录数据使用bincode序列化后存放在数据区，bincode固定使用小端序，usize按8字节存储
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use super::error::{FsError, FsResult};
+use super::virtual_disk::{FatStatus, BLOCK_COUNT, BLOCK_SIZE};
+use super::ROOT_BLOCK;
+
+pub const IMAGE_MAGIC: [u8; 8] = *b"RSFSIMG\0";
+pub const FORMAT_VERSION: u32 = 3;
+// 没有校验和区的旧版本
+const FORMAT_VERSION_WITHOUT_CHECKSUMS: u32 = 2;
+// 没有日志区的旧版本
+const FORMAT_VERSION_WITHOUT_JOURNAL: u32 = 1;
+
+const SUPER_BLOCK_LEN: usize = 60;
+// 块大小的范围，一块至少要放得下超级块和根目录
+pub const MIN_BLOCK_SIZE: usize = 512;
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024 * 16;
+pub const FAT_ENTRY_SIZE: usize = 4;
+// 日志区的最小块数。一次目录更新要记录目录的首块，以及新旧块链涉及的FAT块和校验和块，
+// 改名、复制和碎片整理会同时修改两个目录，日志太小时这些基本操作都会因为事务过大而失败
+pub const MIN_JOURNAL_BLOCKS: usize = 16;
+pub const CHECKSUM_SIZE: usize = 4;
+const FAT_UNUSED: u32 = u32::MAX;
+const FAT_EOF: u32 = u32::MAX - 1;
+
+/// 磁盘参数：块大小、块数量和日志区的块数，在创建文件系统时确定，保存在超级块中
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub block_size: usize,
+    pub block_count: usize,
+    /// 日志区的块数，为0时不使用日志
+    pub journal_blocks: usize,
+}
+
+impl Default for Geometry {
+    fn default() -> Geometry {
+        Geometry {
+            block_size: BLOCK_SIZE,
+            block_count: BLOCK_COUNT,
+            journal_blocks: default_journal_blocks(BLOCK_COUNT),
+        }
+    }
+}
+
+// 默认的日志区块数：数据区的1/32，至少MIN_JOURNAL_BLOCKS块，最多1024块
+fn default_journal_blocks(block_count: usize) -> usize {
+    (block_count / 32).clamp(MIN_JOURNAL_BLOCKS, 1024)
+}
+
+impl Geometry {
+    /// 按块大小和块数量创建磁盘参数，日志区使用默认大小
+    /// 块大小必须是2的幂并且在MIN_BLOCK_SIZE和MAX_BLOCK_SIZE之间，块数量至少为2
+    pub fn new(block_size: usize, block_count: usize) -> FsResult<Geometry> {
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return Err(FsError::InvalidGeometry(format!("bad block size {}", block_size)));
+        }
+        if block_count < 2 || block_count >= FAT_EOF as usize {
+            return Err(FsError::InvalidGeometry(format!("bad block count {}", block_count)));
+        }
+        Ok(Geometry {
+            block_size,
+            block_count,
+            journal_blocks: default_journal_blocks(block_count),
+        })
+    }
+
+    /// 修改日志区的块数，0表示不使用日志，否则至少需要MIN_JOURNAL_BLOCKS块
+    pub fn with_journal_blocks(self, journal_blocks: usize) -> FsResult<Geometry> {
+        if (1..MIN_JOURNAL_BLOCKS).contains(&journal_blocks) || journal_blocks > u32::MAX as usize {
+            return Err(FsError::InvalidGeometry(format!(
+                "bad journal size {}, must be 0 or at least {}",
+                journal_blocks, MIN_JOURNAL_BLOCKS
+            )));
+        }
+        Ok(Geometry { journal_blocks, ..self })
+    }
+
+    /// 按块大小和整个镜像的大小创建磁盘参数，块数量取放得下的最大值
+    pub fn with_total_size(block_size: usize, total_size: u64) -> FsResult<Geometry> {
+        // 每个数据块还要占用FAT区中的一项，先估算再逐步减少
+        let bs: u64 = block_size as u64;
+        let mut block_count: u64 = total_size.saturating_sub(bs) / (bs + FAT_ENTRY_SIZE as u64);
+        let image_len = |block_count: u64| {
+            let block_count: usize = block_count as usize;
+            SuperBlock::new(block_size, block_count, default_journal_blocks(block_count), ROOT_BLOCK).image_len()
+        };
+        while block_count > 0 && image_len(block_count) > total_size {
+            block_count -= 1;
+        }
+        Geometry::new(block_size, block_count as usize)
+    }
+
+    /// 读取镜像文件的超级块，得到镜像的磁盘参数
+    pub fn read_from_image(path: &str) -> FsResult<Geometry> {
+        let mut header: Vec<u8> = Vec::with_capacity(SUPER_BLOCK_LEN);
+        File::open(path)?.take(SUPER_BLOCK_LEN as u64).read_to_end(&mut header)?;
+        Ok(SuperBlock::from_bytes(&header)?.geometry())
+    }
+
+    /// 整个镜像（超级块、FAT区、数据区）需要的块设备块数
+    pub fn device_block_count(&self) -> usize {
+        (self.super_block().image_len() / self.block_size as u64) as usize
+    }
+
+    /// 整个镜像的大小
+    pub fn image_len(&self) -> u64 {
+        self.super_block().image_len()
+    }
+
+    // 按磁盘参数生成超级块
+    pub fn super_block(&self) -> SuperBlock {
+        SuperBlock::new(self.block_size, self.block_count, self.journal_blocks, ROOT_BLOCK)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuperBlock {
+    pub version: u32,
+    pub block_size: u32,
+    pub block_count: u32,
+    pub root_block: u32,
+    pub fat_offset: u64,
+    pub data_offset: u64,
+    pub journal_offset: u64,
+    pub journal_blocks: u32,
+    pub checksum_offset: u64,
+}
+
+impl SuperBlock {
+    // 根据磁盘参数计算各区域的位置
+    pub fn new(block_size: usize, block_count: usize, journal_blocks: usize, root_block: usize) -> SuperBlock {
+        SuperBlock::with_layout(block_size, block_count, journal_blocks, root_block, true)
+    }
+
+    // 根据磁盘参数计算各区域的位置，checksums为false时没有校验和区（版本2以前的布局）
+    fn with_layout(
+        block_size: usize,
+        block_count: usize,
+        journal_blocks: usize,
+        root_block: usize,
+        checksums: bool,
+    ) -> SuperBlock {
+        // FAT区和校验和区都按块大小对齐
+        let region_len = |entry_size: usize| {
+            ((block_count * entry_size) as u64).div_ceil(block_size as u64) * block_size as u64
+        };
+        let fat_offset: u64 = block_size as u64;
+        let checksum_offset: u64 = fat_offset + region_len(FAT_ENTRY_SIZE);
+        let journal_offset: u64 = checksum_offset + if checksums { region_len(CHECKSUM_SIZE) } else { 0 };
+        SuperBlock {
+            version: FORMAT_VERSION,
+            block_size: block_size as u32,
+            block_count: block_count as u32,
+            root_block: root_block as u32,
+            fat_offset,
+            data_offset: journal_offset + (journal_blocks * block_size) as u64,
+            journal_offset,
+            journal_blocks: journal_blocks as u32,
+            checksum_offset,
+        }
+    }
+
+    // 超级块中记录的磁盘参数
+    pub fn geometry(&self) -> Geometry {
+        Geometry {
+            block_size: self.block_size as usize,
+            block_count: self.block_count as usize,
+            journal_blocks: self.journal_blocks as usize,
+        }
+    }
+
+    // 日志区在块设备上的块号范围
+    pub fn journal_range(&self) -> std::ops::Range<usize> {
+        let start: usize = (self.journal_offset / self.block_size as u64) as usize;
+        start..start + self.journal_blocks as usize
+    }
+
+    // 校验和区在块设备上的块号范围，没有校验和区时为空
+    pub fn checksum_range(&self) -> std::ops::Range<usize> {
+        let block_size: u64 = self.block_size as u64;
+        (self.checksum_offset / block_size) as usize..(self.journal_offset / block_size) as usize
+    }
+
+    // 是否记录了数据块的校验和
+    pub fn has_checksums(&self) -> bool {
+        self.journal_offset > self.checksum_offset
+    }
+
+    // FAT区的实际长度
+    pub fn fat_len(&self) -> usize {
+        self.block_count as usize * FAT_ENTRY_SIZE
+    }
+
+    // 整个镜像的长度
+    pub fn image_len(&self) -> u64 {
+        self.data_offset + self.block_count as u64 * self.block_size as u64
+    }
+
+    // 序列化为超级块区域的数据，长度为fat_offset
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::with_capacity(self.fat_offset as usize);
+        data.extend_from_slice(&IMAGE_MAGIC);
+        data.extend_from_slice(&self.version.to_le_bytes());
+        data.extend_from_slice(&self.block_size.to_le_bytes());
+        data.extend_from_slice(&self.block_count.to_le_bytes());
+        data.extend_from_slice(&self.root_block.to_le_bytes());
+        data.extend_from_slice(&self.fat_offset.to_le_bytes());
+        data.extend_from_slice(&self.data_offset.to_le_bytes());
+        data.extend_from_slice(&self.journal_offset.to_le_bytes());
+        data.extend_from_slice(&self.journal_blocks.to_le_bytes());
+        data.extend_from_slice(&self.checksum_offset.to_le_bytes());
+        data.resize(self.fat_offset as usize, 0);
+        data
+    }
+
+    // 从镜像开头的数据解析超级块，并检查各个字段是否合理
+    pub fn from_bytes(data: &[u8]) -> FsResult<SuperBlock> {
+        if data.len() < SUPER_BLOCK_LEN {
+            return Err(FsError::InvalidImage(String::from("image is too small")));
+        }
+        if data[0..8] != IMAGE_MAGIC {
+            return Err(FsError::InvalidImage(String::from("bad magic number")));
+        }
+        let read_u32 = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let read_u64 = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let mut super_block: SuperBlock = SuperBlock {
+            version: read_u32(8),
+            block_size: read_u32(12),
+            block_count: read_u32(16),
+            root_block: read_u32(20),
+            fat_offset: read_u64(24),
+            data_offset: read_u64(32),
+            journal_offset: read_u64(40),
+            journal_blocks: read_u32(48),
+            checksum_offset: read_u64(52),
+        };
+
+        match super_block.version {
+            FORMAT_VERSION => {}
+            FORMAT_VERSION_WITHOUT_CHECKSUMS => {
+                // 旧版本中这些字节是填充的0，校验和区为空，日志区紧接在FAT区之后
+                super_block.checksum_offset = super_block.journal_offset;
+            }
+            FORMAT_VERSION_WITHOUT_JOURNAL => {
+                // 旧版本中这些字节是填充的0，校验和区和日志区都为空，数据区紧接在FAT区之后
+                super_block.journal_offset = super_block.data_offset;
+                super_block.journal_blocks = 0;
+                super_block.checksum_offset = super_block.data_offset;
+            }
+            version => return Err(FsError::InvalidImage(format!("unsupported format version {}", version))),
+        }
+        if Geometry::new(super_block.block_size as usize, super_block.block_count as usize).is_err()
+            || super_block.root_block >= super_block.block_count
+            || super_block.journal_blocks == 1
+        {
+            return Err(FsError::InvalidImage(String::from("bad disk geometry")));
+        }
+        // 各区域的位置必须和按磁盘参数计算出来的一致
+        let expected: SuperBlock = SuperBlock {
+            version: super_block.version,
+            ..SuperBlock::with_layout(
+                super_block.block_size as usize,
+                super_block.block_count as usize,
+                super_block.journal_blocks as usize,
+                super_block.root_block as usize,
+                super_block.version == FORMAT_VERSION,
+            )
+        };
+        if super_block != expected {
+            return Err(FsError::InvalidImage(String::from("bad region offsets")));
+        }
+
+        Ok(super_block)
+    }
+}
+
+// 将FAT编码为FAT区的数据
+pub fn encode_fat(fat: &[FatStatus]) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::with_capacity(fat.len() * FAT_ENTRY_SIZE);
+    for fat_item in fat {
+        let value: u32 = match fat_item {
+            FatStatus::UnUsed => FAT_UNUSED,
+            FatStatus::EOF => FAT_EOF,
+            FatStatus::NextBlock(block) => *block as u32,
+        };
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data
+}
+
+// 从FAT区的数据解码FAT
+// 超出范围的下一块块号原样保留，读取这条块链时才会出错，这样fsck可以发现并修复它
+pub fn decode_fat(data: &[u8], block_count: usize) -> FsResult<Vec<FatStatus>> {
+    let mut fat: Vec<FatStatus> = Vec::with_capacity(block_count);
+    for entry in data.chunks_exact(FAT_ENTRY_SIZE).take(block_count) {
+        let value: u32 = u32::from_le_bytes(entry.try_into().unwrap());
+        fat.push(match value {
+            FAT_UNUSED => FatStatus::UnUsed,
+            FAT_EOF => FatStatus::EOF,
+            next => FatStatus::NextBlock(next as usize),
+        });
+    }
+    if fat.len() != block_count {
+        return Err(FsError::InvalidImage(String::from("FAT region is truncated")));
+    }
+    Ok(fat)
+}
+
+// 数据块的校验和
+pub fn block_checksum(data: &[u8]) -> u32 {
+    crc32c::crc32c(data)
+}
+
+// 将校验和编码为校验和区的数据
+pub fn encode_checksums(checksums: &[u32]) -> Vec<u8> {
+    checksums.iter().flat_map(|checksum| checksum.to_le_bytes()).collect()
+}
+
+// 从校验和区的数据解码校验和
+pub fn decode_checksums(data: &[u8], block_count: usize) -> Vec<u32> {
+    data.chunks_exact(CHECKSUM_SIZE)
+        .take(block_count)
+        .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+        .collect()
+}
+
+/// 第generation代备份镜像的路径，1是最近一次保存之前的镜像
+pub fn backup_path(path: &str, generation: usize) -> String {
+    format!("{}.bak{}", path, generation)
+}
+
+/// 把镜像数据原子地写入path：先写入同一目录下的临时文件并落盘，再改名覆盖原来的镜像，最后把目录落盘
+/// 中途失败或断电时path要么还是原来的镜像，要么已经是完整的新镜像
+/// generations大于0时把原来的镜像保留为path.bak1，已有的备份依次后移，最多保留generations代
+pub fn write_image_file(path: &str, data: &[u8], generations: usize) -> FsResult<()> {
+    let temp_path: String = format!("{}.tmp", path);
+    if let Err(err) = write_and_sync(&temp_path, data) {
+        // 临时文件不完整，删除失败也不影响原来的镜像
+        let _ = fs::remove_file(&temp_path);
+        return Err(err);
+    }
+
+    if generations > 0 && Path::new(path).exists() {
+        rotate_backups(path, generations)?;
+    }
+    fs::rename(&temp_path, path)?;
+    sync_parent_directory(path)
+}
+
+// 写入文件并等待数据落盘
+fn write_and_sync(path: &str, data: &[u8]) -> FsResult<()> {
+    let mut file: File = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// 备份依次后移一代，超出generations的最旧的一代被覆盖，然后把当前镜像保留为第1代
+// 当前镜像通过硬链接保留，不需要复制数据，改名覆盖path之前path始终有效
+fn rotate_backups(path: &str, generations: usize) -> FsResult<()> {
+    for generation in (1..generations).rev() {
+        let from: String = backup_path(path, generation);
+        if Path::new(&from).exists() {
+            fs::rename(&from, backup_path(path, generation + 1))?;
+        }
+    }
+    let newest: String = backup_path(path, 1);
+    if Path::new(&newest).exists() {
+        fs::remove_file(&newest)?;
+    }
+    if fs::hard_link(path, &newest).is_err() {
+        // 不支持硬链接的文件系统上复制一份
+        let data: Vec<u8> = fs::read(path)?;
+        write_and_sync(&newest, &data)?;
+    }
+    Ok(())
+}
+
+// 把path所在的目录落盘，这样改名本身在断电后也不会丢失
+#[cfg(unix)]
+fn sync_parent_directory(path: &str) -> FsResult<()> {
+    let parent: &Path = match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+// 其他平台上无法打开目录，改名后由操作系统负责
+#[cfg(not(unix))]
+fn sync_parent_directory(_path: &str) -> FsResult<()> {
+    Ok(())
+}
@@ -0,0 +1,155 @@
+// 日志区的格式
+//
+// 日志区的第一块是日志头，之后的块依次存放一个事务中每一块的新数据
+//   偏移  长度  内容
+//   0     8     魔数 "RSFSJRNL"
+//   8     4     块数量n
+//   12    4     保留，为0
+//   16    8     校验和：块号和新数据的FNV-1a散列
+//   24    8n    每一块新数据在块设备上的目标块号
+//
+// 提交事务时先把新数据写入日志区并落盘，再写入日志头并落盘，日志头落盘就表示事务已经提交；
+// 之后把新数据写到目标块并落盘，最后清空日志头。
+// 打开镜像时如果日志头有效并且校验和正确，就把新数据重新写一遍。写了一半的日志校验和不对，直接丢弃，
+// 所以一个事务要么全部生效，要么全部没有生效。重复写入已经提交的事务不会有任何影响
+
+use std::collections::{BTreeMap, HashMap};
+use super::block_device::BlockDevice;
+use super::error::{FsError, FsResult};
+use super::image::SuperBlock;
+use super::virtual_disk::FatStatus;
+
+pub const JOURNAL_MAGIC: [u8; 8] = *b"RSFSJRNL";
+
+const HEADER_LEN: usize = 24;
+const TARGET_SIZE: usize = 8;
+
+// 正在进行的事务：修改过的FAT项的原值，以及还没有写到块设备上的块
+#[derive(Debug, Default)]
+pub(crate) struct Transaction {
+    // 数据区块号 -> 事务开始前的FAT项，撤销时恢复
+    pub fat_original: HashMap<usize, FatStatus>,
+    // 数据区块号 -> 事务开始前的校验和，撤销时恢复
+    pub checksum_original: HashMap<usize, u32>,
+    // 块设备块号 -> 新数据，提交时通过日志写入
+    pub writes: BTreeMap<usize, Vec<u8>>,
+}
+
+impl Transaction {
+    // 数据区的块在事务开始时是否空闲。这样的块写入后即使没有提交也不会破坏已有的数据，可以直接写入
+    pub fn is_fresh(&self, block: usize) -> bool {
+        matches!(self.fat_original.get(&block), Some(FatStatus::UnUsed))
+    }
+}
+
+/// 一个事务最多能修改的块数，受日志区大小和日志头中能记录的块号数量限制
+pub fn journal_capacity(super_block: &SuperBlock) -> usize {
+    let journal_blocks: usize = super_block.journal_blocks as usize;
+    if journal_blocks < 2 {
+        return 0;
+    }
+    (journal_blocks - 1).min((super_block.block_size as usize - HEADER_LEN) / TARGET_SIZE)
+}
+
+// 块号和新数据的FNV-1a散列
+fn checksum(writes: &[(usize, Vec<u8>)]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for (block, data) in writes {
+        for byte in (*block as u64).to_le_bytes().iter().chain(data.iter()) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// 通过日志把writes中的每一块写入块设备：全部写入成功，或者崩溃后重新打开时全部没有写入
+/// 日志区为空时直接写入
+pub fn write_journaled<D: BlockDevice>(
+    device: &mut D,
+    super_block: &SuperBlock,
+    writes: &[(usize, Vec<u8>)],
+) -> FsResult<()> {
+    if writes.is_empty() {
+        return device.flush();
+    }
+    if super_block.journal_blocks == 0 {
+        for (block, data) in writes {
+            device.write_block(*block, data)?;
+        }
+        return device.flush();
+    }
+    if writes.len() > journal_capacity(super_block) {
+        return Err(FsError::TransactionTooLarge(writes.len()));
+    }
+
+    let journal_start: usize = super_block.journal_range().start;
+    for (i, (_, data)) in writes.iter().enumerate() {
+        device.write_block(journal_start + 1 + i, data)?;
+    }
+    device.flush()?;
+
+    // 写入日志头，事务在这里提交
+    let mut header: Vec<u8> = Vec::with_capacity(HEADER_LEN + writes.len() * TARGET_SIZE);
+    header.extend_from_slice(&JOURNAL_MAGIC);
+    header.extend_from_slice(&(writes.len() as u32).to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&checksum(writes).to_le_bytes());
+    for (block, _) in writes {
+        header.extend_from_slice(&(*block as u64).to_le_bytes());
+    }
+    device.write_block(journal_start, &header)?;
+    device.flush()?;
+
+    for (block, data) in writes {
+        device.write_block(*block, data)?;
+    }
+    device.flush()?;
+    // 清空日志头。不需要立即落盘，崩溃后重新写一遍已经写过的数据也没有影响
+    device.write_block(journal_start, &vec![0u8; device.block_size()])
+}
+
+/// 打开镜像时重新写入已经提交的事务，返回写入的块数，没有需要重新写入的事务时返回0
+pub fn replay_journal<D: BlockDevice>(device: &mut D, super_block: &SuperBlock) -> FsResult<usize> {
+    let capacity: usize = journal_capacity(super_block);
+    if capacity == 0 {
+        return Ok(0);
+    }
+    let journal_start: usize = super_block.journal_range().start;
+    let header: Vec<u8> = device.read_block(journal_start)?;
+    if header[0..8] != JOURNAL_MAGIC {
+        return Ok(0);
+    }
+    let read_u64 = |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+    let count: usize = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+    if count == 0 || count > capacity {
+        return Ok(0);
+    }
+
+    // 目标块只能在FAT区和数据区中
+    let journal = super_block.journal_range();
+    let device_blocks: usize = device.block_count();
+    let valid_target = |block: u64| block != 0 && block < device_blocks as u64 && !journal.contains(&(block as usize));
+    let mut writes: Vec<(usize, Vec<u8>)> = Vec::with_capacity(count);
+    for i in 0..count {
+        let block: u64 = read_u64(HEADER_LEN + i * TARGET_SIZE);
+        if !valid_target(block) {
+            break;
+        }
+        writes.push((block as usize, device.read_block(journal_start + 1 + i)?));
+    }
+    if writes.len() != count || checksum(&writes) != read_u64(16) {
+        // 事务没有提交完成，丢弃
+        device.write_block(journal_start, &vec![0u8; device.block_size()])?;
+        device.flush()?;
+        return Ok(0);
+    }
+
+    for (block, data) in &writes {
+        device.write_block(*block, data)?;
+    }
+    device.flush()?;
+    device.write_block(journal_start, &vec![0u8; device.block_size()])?;
+    device.flush()?;
+    Ok(count)
+}
@@ -0,0 +1,56 @@
+use std::fmt;
+
+/// 数据和校验和不一致的块
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadBlock {
+    /// 块号
+    pub block: usize,
+    /// 这一块所属的文件或目录，不属于任何文件时为None
+    pub path: Option<String>,
+}
+
+impl fmt::Display for BadBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "block {} of {}", self.block, path),
+            None => write!(f, "block {} (not referenced by any file)", self.block),
+        }
+    }
+}
+
+/// scrub的结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// 镜像是否记录了校验和，旧版本的镜像没有校验和，不做检查
+    pub has_checksums: bool,
+    /// 检查过的块数（所有已使用的块）
+    pub blocks_checked: usize,
+    /// 校验和不一致的块
+    pub bad_blocks: Vec<BadBlock>,
+}
+
+impl ScrubReport {
+    /// 是否所有块的校验和都正确
+    pub fn is_clean(&self) -> bool {
+        self.bad_blocks.is_empty()
+    }
+}
+
+impl fmt::Display for ScrubReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.has_checksums {
+            return write!(f, "image has no block checksums, nothing to verify");
+        }
+        if self.bad_blocks.is_empty() {
+            return write!(f, "{} blocks checked, all checksums match", self.blocks_checked);
+        }
+        writeln!(f, "{} blocks checked, {} checksum mismatches:", self.blocks_checked, self.bad_blocks.len())?;
+        for (i, bad_block) in self.bad_blocks.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "  {}", bad_block)?;
+        }
+        Ok(())
+    }
+}
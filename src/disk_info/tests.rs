@@ -0,0 +1,595 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::{fs, io, process};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use super::allocation::AllocationPolicy;
+use super::block_device::{BlockDevice, FileDevice};
+use super::error::{FsError, FsResult};
+use super::file_handle::OpenFlags;
+use super::free_space::FreeBitmap;
+use super::fsck::FsckProblem;
+use super::image::{Geometry, SuperBlock};
+use super::virtual_disk::{FatStatus, VirtualDisk};
+use super::{Directory, DiskInfo, FileType, ROOT_BLOCK};
+
+// 测试用的镜像文件，drop时删除
+struct TempImage(PathBuf);
+
+impl TempImage {
+    fn new(name: &str) -> TempImage {
+        let path: PathBuf = std::env::temp_dir().join(format!("rust_file_system-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        TempImage(path)
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempImage {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// 模拟断电：写入writes_left块之后所有的写入和落盘都失败
+struct CrashDevice<D: BlockDevice> {
+    device: D,
+    writes_left: usize,
+}
+
+impl<D: BlockDevice> BlockDevice for CrashDevice<D> {
+    fn read_block(&self, block: usize) -> FsResult<Vec<u8>> {
+        self.device.read_block(block)
+    }
+
+    fn write_block(&mut self, block: usize, data: &[u8]) -> FsResult<()> {
+        if self.writes_left == 0 {
+            return Err(FsError::Io(io::ErrorKind::Other, String::from("power lost")));
+        }
+        self.writes_left -= 1;
+        self.device.write_block(block, data)
+    }
+
+    fn block_count(&self) -> usize {
+        self.device.block_count()
+    }
+
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn flush(&mut self) -> FsResult<()> {
+        if self.writes_left == 0 {
+            return Err(FsError::Io(io::ErrorKind::Other, String::from("power lost")));
+        }
+        self.device.flush()
+    }
+}
+
+// 检查镜像没有损坏
+fn assert_consistent<D: BlockDevice>(disk: &mut DiskInfo<D>) {
+    let report = disk.fsck(false).unwrap();
+    assert!(report.is_clean(), "{}", report);
+    let report = disk.scrub().unwrap();
+    assert!(report.is_clean(), "{}", report);
+}
+
+// 测试数据，不含0xFF
+fn sample(seed: usize, length: usize) -> Vec<u8> {
+    (0..length).map(|i| ((i * 7 + seed) % 250) as u8).collect()
+}
+
+#[test]
+fn failed_allocation_leaves_fat_unchanged() {
+    let mut disk = DiskInfo::format(Geometry::new(512, 128).unwrap()).unwrap();
+    // 在/d中新建空文件，直到再加一个目录项/d就要多占一块
+    disk.new_directory_to_disk("/d").unwrap();
+    for i in 0.. {
+        let dir_len: usize = bincode::serialize(&disk.get_directory_by_path("/d").unwrap()).unwrap().len();
+        if dir_len % 512 + 44 > 512 {
+            break;
+        }
+        disk.create_file_with_data(&format!("/d/empty-{:04}", i), b"").unwrap();
+    }
+    let fat: Vec<FatStatus> = disk.virtual_disk.fat().to_vec();
+    let free_blocks: usize = disk.virtual_disk.free_block_count();
+
+    // 数据正好占满所有空闲块，分配到一半时/d没有空间了
+    let data: Vec<u8> = vec![1u8; free_blocks * 512];
+    assert_eq!(disk.create_file_with_data("/d/partly-alloc", &data), Err(FsError::NoSpace));
+    assert_eq!(disk.virtual_disk.fat(), &fat[..]);
+    assert_eq!(disk.virtual_disk.free_block_count(), free_blocks);
+    assert_eq!(disk.read_file_by_name("/d/partly-alloc"), Err(FsError::NotFound(String::from("/d/partly-alloc"))));
+
+    // 比空闲块多一块时一块也不分配
+    assert_eq!(disk.allocate_free_space_on_fat(free_blocks + 1), Err(FsError::NoSpace));
+    assert_eq!(disk.virtual_disk.fat(), &fat[..]);
+    assert_eq!(disk.virtual_disk.free_block_count(), free_blocks);
+    assert_eq!(disk.find_next_empty_fat(), fat.iter().position(|fat_item| *fat_item == FatStatus::UnUsed));
+    assert_consistent(&mut disk);
+}
+
+#[test]
+fn each_policy_picks_its_free_run() {
+    // 空闲段：4..6（2块）、10..15（5块）、20..23（3块）、30..64（34块）
+    let fat: Vec<FatStatus> = (0..64)
+        .map(|block| match block {
+            4..6 | 10..15 | 20..23 | 30..64 => FatStatus::UnUsed,
+            _ => FatStatus::EOF,
+        })
+        .collect();
+    let free_space: FreeBitmap = FreeBitmap::from_fat(&fat);
+    let choose = |policy: AllocationPolicy, cursor: usize| policy.choose_blocks(&free_space, cursor, 3).unwrap();
+
+    assert_eq!(choose(AllocationPolicy::FirstFit, 12), vec![4, 5, 10]);
+    assert_eq!(choose(AllocationPolicy::NextFit, 12), vec![12, 13, 14]);
+    assert_eq!(choose(AllocationPolicy::NextFit, 62), vec![62, 63, 4]);
+    assert_eq!(choose(AllocationPolicy::BestFit, 0), vec![20, 21, 22]);
+    assert_eq!(choose(AllocationPolicy::WorstFit, 0), vec![30, 31, 32]);
+    assert_eq!(choose(AllocationPolicy::Contiguous, 0), vec![10, 11, 12]);
+
+    // 每种策略都放得下最多空闲块数的数据，再多一块就放不下
+    for policy in AllocationPolicy::ALL {
+        assert_eq!(policy.choose_blocks(&free_space, 0, 44).map(|blocks| blocks.len()), Some(44));
+        assert_eq!(policy.choose_blocks(&free_space, 0, 45), None);
+    }
+}
+
+// 遍历目录树，检查每个目录的".."和"."指向正确的块，返回所有文件的内容
+fn read_tree(disk: &DiskInfo) -> HashMap<String, Vec<u8>> {
+    let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut pending: Vec<(String, usize, usize)> = vec![(String::new(), ROOT_BLOCK, ROOT_BLOCK)];
+    while let Some((path, first_block, parent_block)) = pending.pop() {
+        let dir: Directory = disk.read_directory_from_disk(first_block).unwrap();
+        assert_eq!(dir.files[0].first_block, parent_block, "'..' of '{}/'", path);
+        assert_eq!(dir.files[1].first_block, first_block, "'.' of '{}/'", path);
+        for fcb in &dir.files[2..] {
+            let child: String = format!("{}/{}", path, fcb.name);
+            match fcb.file_type {
+                FileType::Directory => pending.push((child, fcb.first_block, first_block)),
+                FileType::File => {
+                    let data: Vec<u8> = disk.read_file_by_name(&child).unwrap();
+                    files.insert(child, data);
+                }
+            }
+        }
+    }
+    files
+}
+
+// 交替写入文件再删掉一半，之后新建的文件和目录只能放进很短的空闲段中
+fn fragmented_disk() -> DiskInfo {
+    let mut disk = DiskInfo::format(Geometry::new(512, 256).unwrap()).unwrap();
+    disk.new_directory_to_disk("/a").unwrap();
+    disk.new_directory_to_disk("/a/b").unwrap();
+    for i in 0..12 {
+        disk.create_file_with_data(&format!("/a/tmp{}", i), &sample(i, 700)).unwrap();
+        disk.create_file_with_data(&format!("/a/b/keep{}", i), &sample(i, 900)).unwrap();
+    }
+    for i in 0..12 {
+        disk.delete_file_by_name(&format!("/a/tmp{}", i)).unwrap();
+    }
+    disk.create_file_with_data("/big", &sample(99, 5000)).unwrap();
+    disk.new_directory_to_disk("/a/c").unwrap();
+    disk.create_file_with_data("/a/c/f", &sample(7, 3000)).unwrap();
+    disk.change_current_directory("/a/c").unwrap();
+    assert!(disk.fragmentation_stats().fragmented_chains > 0);
+    disk
+}
+
+#[test]
+fn defragment_makes_every_chain_contiguous() {
+    let mut disk = fragmented_disk();
+    let files: HashMap<String, Vec<u8>> = read_tree(&disk);
+    let report = disk.defragment(|_| true).unwrap();
+    assert!(report.completed, "{}", report);
+    assert!(report.blocks_moved > 0);
+    assert_eq!(report.files_skipped, 0);
+    assert_eq!(disk.fragmentation_stats().fragmented_chains, 0);
+
+    // 内容不变，FCB的首块和目录的".."、"."都指向移动后的块，当前目录仍然可用
+    assert_eq!(read_tree(&disk), files);
+    assert_eq!(disk.current_path(), "/a/c");
+    assert_eq!(disk.read_file_by_name("f").unwrap(), sample(7, 3000));
+    disk.create_file_with_data("g", b"after defrag").unwrap();
+    assert_eq!(disk.read_file_by_name("/a/c/g").unwrap(), b"after defrag");
+    assert_consistent(&mut disk);
+}
+
+#[test]
+fn defragment_can_stop_and_resume() {
+    let mut disk = fragmented_disk();
+    let files: HashMap<String, Vec<u8>> = read_tree(&disk);
+    let before: usize = disk.fragmentation_stats().extents;
+
+    // 和defrag max_blocks一样，移动了至少3块之后停止
+    let report = disk.defragment(|report| report.blocks_moved < 3).unwrap();
+    assert!(!report.completed, "{}", report);
+    assert!(report.blocks_moved >= 3);
+    assert!(report.files_checked < report.files_total);
+    assert!(disk.fragmentation_stats().extents < before);
+    assert_eq!(read_tree(&disk), files);
+    assert_consistent(&mut disk);
+
+    // 再次整理时完成剩下的部分
+    let report = disk.defragment(|_| true).unwrap();
+    assert!(report.completed, "{}", report);
+    assert_eq!(disk.fragmentation_stats().fragmented_chains, 0);
+    assert_eq!(read_tree(&disk), files);
+    assert_consistent(&mut disk);
+}
+
+// 新建/d/f（3块）、/d/g和空目录/d/e，返回/d/f的块链
+fn disk_with_files() -> (DiskInfo, Vec<usize>) {
+    let mut disk = DiskInfo::format(Geometry::new(512, 128).unwrap()).unwrap();
+    disk.new_directory_to_disk("/d").unwrap();
+    disk.new_directory_to_disk("/d/e").unwrap();
+    disk.create_file_with_data("/d/f", &sample(1, 1500)).unwrap();
+    disk.create_file_with_data("/d/g", &sample(2, 700)).unwrap();
+    let first_block: usize = disk.resolve_fcb("/d/f").unwrap().1.first_block;
+    let chain: Vec<usize> = disk.get_file_blocks(first_block).unwrap();
+    assert_eq!(chain.len(), 3);
+    (disk, chain)
+}
+
+// 绕过DiskInfo直接修改磁盘上的目录
+fn corrupt_directory<F: FnOnce(&mut Directory)>(disk: &mut DiskInfo, path: &str, corrupt: F) {
+    let first_block: usize = disk.resolve_fcb(path).unwrap().1.first_block;
+    let mut dir: Directory = disk.read_directory_from_disk(first_block).unwrap();
+    corrupt(&mut dir);
+    let data: Vec<u8> = bincode::serialize(&dir).unwrap();
+    disk.rewrite_data_on_disk(first_block, &data).unwrap();
+}
+
+// fsck(false)能发现expected，fsck(true)修复之后没有问题
+fn assert_repaired(disk: &mut DiskInfo, expected: FsckProblem) {
+    let report = disk.fsck(false).unwrap();
+    assert!(report.problems.contains(&expected), "{:?} not in {}", expected, report);
+    assert!(!report.is_clean());
+    assert_eq!(report.repaired, 0);
+
+    let report = disk.fsck(true).unwrap();
+    assert!(report.problems.contains(&expected), "{:?} not in {}", expected, report);
+    assert!(report.repaired > 0);
+    assert!(report.is_clean(), "{}", report);
+    assert_consistent(disk);
+}
+
+#[test]
+fn fsck_repairs_chain_out_of_range() {
+    let (mut disk, chain) = disk_with_files();
+    disk.virtual_disk.set_fat(chain[1], FatStatus::NextBlock(1000));
+    let path: String = String::from("/d/f");
+    assert_repaired(&mut disk, FsckProblem::OutOfRange { path, next: 1000 });
+}
+
+#[test]
+fn fsck_repairs_unused_block_in_chain() {
+    let (mut disk, chain) = disk_with_files();
+    let unused: usize = disk.find_next_empty_fat().unwrap();
+    disk.virtual_disk.set_fat(chain[0], FatStatus::NextBlock(unused));
+    let path: String = String::from("/d/f");
+    assert_repaired(&mut disk, FsckProblem::UnusedInChain { path, block: unused });
+}
+
+#[test]
+fn fsck_repairs_cycle() {
+    let (mut disk, chain) = disk_with_files();
+    disk.virtual_disk.set_fat(chain[2], FatStatus::NextBlock(chain[0]));
+    let path: String = String::from("/d/f");
+    assert_repaired(&mut disk, FsckProblem::Cycle { path, block: chain[0] });
+}
+
+#[test]
+fn fsck_repairs_shared_chain() {
+    let (mut disk, chain) = disk_with_files();
+    corrupt_directory(&mut disk, "/d", |dir| {
+        let fcb = dir.files.iter_mut().find(|fcb| fcb.name == "g").unwrap();
+        fcb.first_block = chain[1];
+    });
+    let (path, other) = (String::from("/d/g"), String::from("/d/f"));
+    assert_repaired(&mut disk, FsckProblem::SharedChain { path, other, block: chain[1] });
+    // 两个文件都还能读出，/d/g得到的是/d/f第二块开始的数据的副本
+    assert_eq!(disk.read_file_by_name("/d/f").unwrap(), sample(1, 1500));
+    assert!(sample(1, 1500)[512..].starts_with(&disk.read_file_by_name("/d/g").unwrap()));
+}
+
+#[test]
+fn fsck_repairs_length_mismatch() {
+    let (mut disk, _) = disk_with_files();
+    corrupt_directory(&mut disk, "/d", |dir| {
+        let fcb = dir.files.iter_mut().find(|fcb| fcb.name == "f").unwrap();
+        fcb.length = 5000;
+    });
+    let path: String = String::from("/d/f");
+    assert_repaired(&mut disk, FsckProblem::LengthMismatch { path, length: 5000, blocks: 3 });
+    assert_eq!(disk.read_file_by_name("/d/f").unwrap()[..1500], sample(1, 1500)[..]);
+}
+
+#[test]
+fn fsck_repairs_broken_link() {
+    let (mut disk, chain) = disk_with_files();
+    corrupt_directory(&mut disk, "/d/e", |dir| dir.files[0].first_block = chain[0]);
+    let (path, name) = (String::from("/d/e"), String::from(".."));
+    let expected: usize = disk.resolve_fcb("/d").unwrap().1.first_block;
+    assert_repaired(&mut disk, FsckProblem::BrokenLink { path, name, expected, found: chain[0] });
+    disk.change_current_directory("/d/e").unwrap();
+    disk.change_current_directory("..").unwrap();
+    assert_eq!(disk.current_path(), "/d");
+}
+
+#[test]
+fn fsck_repairs_unreadable_directory() {
+    let (mut disk, _) = disk_with_files();
+    let first_block: usize = disk.resolve_fcb("/d/e").unwrap().1.first_block;
+    disk.virtual_disk.insert_data_by_block(&[0xAB; 512], first_block).unwrap();
+    let path: String = String::from("/d/e");
+    assert_repaired(&mut disk, FsckProblem::UnreadableDirectory { path });
+    assert_eq!(disk.read_file_by_name("/d/e"), Err(FsError::NotFound(String::from("/d/e"))));
+}
+
+#[test]
+fn fsck_repairs_leaked_blocks() {
+    let (mut disk, _) = disk_with_files();
+    let leaked: usize = disk.find_next_empty_fat().unwrap();
+    disk.virtual_disk.set_fat(leaked, FatStatus::EOF);
+    assert_repaired(&mut disk, FsckProblem::LeakedBlocks { blocks: vec![leaked] });
+    assert_eq!(disk.find_next_empty_fat(), Some(leaked));
+}
+
+#[test]
+fn uncommitted_transaction_is_discarded_after_reopen() {
+    let image: TempImage = TempImage::new("uncommitted");
+    let mut disk = DiskInfo::create(image.path(), Geometry::new(512, 256).unwrap()).unwrap();
+    disk.new_directory_to_disk("/a").unwrap();
+    disk.create_file_with_data("/a/f", b"committed").unwrap();
+
+    // 在事务中改名并新建文件，不提交就丢掉DiskInfo
+    assert!(disk.virtual_disk.begin_transaction());
+    disk.rename_file_by_name_in_transaction("/a/f", "/g").unwrap();
+    disk.create_file_with_data_in_transaction("/a/h", &[7u8; 2000]).unwrap();
+    drop(disk);
+
+    let mut disk = DiskInfo::open(image.path()).unwrap();
+    assert_eq!(disk.read_file_by_name("/a/f").unwrap(), b"committed");
+    assert_eq!(disk.read_file_by_name("/g"), Err(FsError::NotFound(String::from("/g"))));
+    assert_eq!(disk.read_file_by_name("/a/h"), Err(FsError::NotFound(String::from("/a/h"))));
+    assert_consistent(&mut disk);
+}
+
+#[test]
+fn transaction_is_all_or_nothing_at_every_crash_point() {
+    let image: TempImage = TempImage::new("crash");
+    let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    for writes_left in 0.. {
+        {
+            let mut disk = DiskInfo::create(image.path(), Geometry::new(512, 256).unwrap()).unwrap();
+            disk.new_directory_to_disk("/a").unwrap();
+            disk.new_directory_to_disk("/b").unwrap();
+            disk.create_file_with_data("/a/f", &data).unwrap();
+            disk.flush().unwrap();
+        }
+
+        // 跨目录改名同时修改两个目录，中途断电后重新打开
+        let block_size: usize = Geometry::read_from_image(image.path()).unwrap().block_size;
+        let device = CrashDevice {
+            device: FileDevice::open(image.path(), block_size).unwrap(),
+            writes_left,
+        };
+        let mut disk = DiskInfo::open_device(device).unwrap();
+        let finished: bool = disk.rename_file_by_name("/a/f", "/b/g").is_ok();
+        drop(disk);
+
+        let mut disk = DiskInfo::open(image.path()).unwrap();
+        let old = disk.read_file_by_name("/a/f");
+        let new = disk.read_file_by_name("/b/g");
+        match (old, new) {
+            (Ok(old), Err(FsError::NotFound(_))) if !finished => assert_eq!(old, data),
+            (Err(FsError::NotFound(_)), Ok(new)) => assert_eq!(new, data),
+            (old, new) => panic!("crash after {} writes left {:?} and {:?}", writes_left, old, new),
+        }
+        assert_consistent(&mut disk);
+        if finished {
+            break;
+        }
+    }
+}
+
+#[test]
+fn staged_block_keeps_later_writes_after_fat_changes() {
+    // 碎片整理先写入空闲的目标块再修改FAT，之后对这一块的写入不能被先前保存在内存中的数据覆盖
+    let mut disk = VirtualDisk::with_geometry(Geometry::new(512, 64).unwrap()).unwrap();
+    let block: usize = disk.first_free_block().unwrap();
+    assert!(disk.begin_transaction());
+    disk.insert_data_by_block(&[1u8; 512], block).unwrap();
+    disk.set_fat(block, FatStatus::EOF);
+    disk.insert_data_by_block(&[2u8; 512], block).unwrap();
+    assert_eq!(disk.read_data_by_block(block).unwrap(), vec![2u8; 512]);
+    disk.commit_transaction().unwrap();
+    assert_eq!(disk.read_data_by_block(block).unwrap(), vec![2u8; 512]);
+}
+
+#[test]
+fn journal_must_hold_a_directory_update() {
+    let geometry: Geometry = Geometry::new(512, 2000).unwrap();
+    assert!(matches!(geometry.with_journal_blocks(6), Err(FsError::InvalidGeometry(_))));
+    assert!(geometry.with_journal_blocks(0).is_ok());
+
+    let mut disk = DiskInfo::format(geometry.with_journal_blocks(16).unwrap()).unwrap();
+    disk.new_directory_to_disk("/a").unwrap();
+    disk.create_file_with_data("/a/f", &[1u8; 5000]).unwrap();
+    disk.rename_file_by_name("/a/f", "/f").unwrap();
+    assert_eq!(disk.read_file_by_name("/f").unwrap(), vec![1u8; 5000]);
+}
+
+#[test]
+fn corrupted_block_is_reported_with_its_file() {
+    let mut disk = DiskInfo::format(Geometry::new(512, 128).unwrap()).unwrap();
+    disk.new_directory_to_disk("/d").unwrap();
+    disk.create_file_with_data("/d/f", &sample(3, 1500)).unwrap();
+    let first_block: usize = disk.resolve_fcb("/d/f").unwrap().1.first_block;
+    let block: usize = disk.get_file_blocks(first_block).unwrap()[1];
+
+    // 绕过校验和直接修改镜像中这一块的一个字节
+    let mut image: Vec<u8> = disk.to_image().unwrap();
+    let data_offset: usize = SuperBlock::from_bytes(&image).unwrap().data_offset as usize;
+    image[data_offset + block * 512 + 100] ^= 0x01;
+    let mut disk = DiskInfo::from_image(&image).unwrap();
+
+    assert_eq!(disk.read_file_by_name("/d/f"), Err(FsError::BadChecksum(String::from("f"), block)));
+    let report = disk.scrub().unwrap();
+    assert!(report.has_checksums);
+    assert_eq!(report.bad_blocks.len(), 1, "{}", report);
+    assert_eq!(report.bad_blocks[0].block, block);
+    assert_eq!(report.bad_blocks[0].path.as_deref(), Some("/d/f"));
+    assert!(!report.is_clean());
+}
+
+// 各种长度的二进制数据：块大小附近的边界长度和随机长度，内容包括随机字节、以0xFF结尾、全是0xFF
+fn binary_samples(block_size: usize, rng: &mut StdRng) -> Vec<Vec<u8>> {
+    let mut lengths: Vec<usize> = vec![0, 1, block_size - 1, block_size, block_size + 1, 2 * block_size, 3 * block_size];
+    lengths.push(rng.gen_range(4..8) * block_size);
+    lengths.extend((0..8).map(|_| rng.gen_range(0..5 * block_size)));
+    let mut samples: Vec<Vec<u8>> = Vec::new();
+    for length in lengths {
+        let mut data: Vec<u8> = vec![0u8; length];
+        rng.fill(&mut data[..]);
+        samples.push(data.clone());
+        if length > 0 {
+            // 中间有连续的0xFF并且以0xFF结尾，旧格式会把它们当成文件结束标志
+            let start: usize = rng.gen_range(0..length);
+            let end: usize = rng.gen_range(start..=length);
+            data[start..end].fill(0xFF);
+            data[length - 1] = 0xFF;
+            samples.push(data);
+            samples.push(vec![0xFF; length]);
+        }
+    }
+    samples
+}
+
+#[test]
+fn binary_files_round_trip() {
+    for block_size in [512, 4096] {
+        let mut rng: StdRng = StdRng::seed_from_u64(block_size as u64);
+        let samples: Vec<Vec<u8>> = binary_samples(block_size, &mut rng);
+        let mut disk = DiskInfo::format(Geometry::new(block_size, 1024).unwrap()).unwrap();
+        disk.new_directory_to_disk("/bin").unwrap();
+        for (i, data) in samples.iter().enumerate() {
+            let path: String = format!("/bin/{}", i);
+            disk.create_file_with_data(&path, data).unwrap();
+            assert_eq!(&disk.read_file_by_name(&path).unwrap(), data, "length {}", data.len());
+        }
+
+        // 保存成镜像再读回来，内容和长度都不变
+        let image: Vec<u8> = disk.to_image().unwrap();
+        let mut disk = DiskInfo::from_image(&image).unwrap();
+        for (i, data) in samples.iter().enumerate() {
+            let path: String = format!("/bin/{}", i);
+            assert_eq!(&disk.read_file_by_name(&path).unwrap(), data, "length {}", data.len());
+            assert_eq!(disk.resolve_fcb(&path).unwrap().1.length, data.len());
+        }
+        assert_consistent(&mut disk);
+    }
+}
+
+#[test]
+fn write_at_spans_blocks() {
+    let mut disk = DiskInfo::format(Geometry::new(512, 128).unwrap()).unwrap();
+    disk.create_file_with_data("/f", &sample(1, 1500)).unwrap();
+    let handle = disk.open_file("/f", OpenFlags::READ_WRITE).unwrap();
+
+    // 从第一块中间写到第三块中间
+    let mut expected: Vec<u8> = sample(1, 1500);
+    expected[400..1100].copy_from_slice(&sample(2, 700));
+    assert_eq!(disk.write_at(handle, 400, &sample(2, 700)).unwrap(), 700);
+    assert_eq!(disk.file_length(handle).unwrap(), 1500);
+    assert_eq!(disk.read_file_by_name("/f").unwrap(), expected);
+
+    // 跨块读出一部分
+    let mut buf: Vec<u8> = vec![0u8; 600];
+    assert_eq!(disk.read_at(handle, 300, &mut buf).unwrap(), 600);
+    assert_eq!(buf, expected[300..900]);
+    assert_eq!(disk.read_at(handle, 1400, &mut buf).unwrap(), 100);
+    disk.close(handle).unwrap();
+    assert_consistent(&mut disk);
+}
+
+#[test]
+fn write_past_the_end_fills_the_hole_with_zeros() {
+    let mut disk = DiskInfo::format(Geometry::new(512, 128).unwrap()).unwrap();
+    let handle = disk.open_file("/f", OpenFlags::READ_WRITE.with_create()).unwrap();
+    assert_eq!(disk.write(handle, b"abc").unwrap(), 3);
+    assert_eq!(disk.write_at(handle, 2000, b"xyz").unwrap(), 3);
+    assert_eq!(disk.file_length(handle).unwrap(), 2003);
+
+    let data: Vec<u8> = disk.read_file_by_name("/f").unwrap();
+    assert_eq!(&data[..3], b"abc");
+    assert!(data[3..2000].iter().all(|byte| *byte == 0));
+    assert_eq!(&data[2000..], b"xyz");
+    assert_eq!(disk.resolve_fcb("/f").unwrap().1.length, 2003);
+    assert_consistent(&mut disk);
+}
+
+#[test]
+fn truncate_frees_blocks() {
+    let mut disk = DiskInfo::format(Geometry::new(512, 128).unwrap()).unwrap();
+    disk.create_file_with_data("/f", &sample(1, 5000)).unwrap();
+    let free_blocks: usize = disk.virtual_disk.free_block_count();
+    let handle = disk.open_file("/f", OpenFlags::READ_WRITE).unwrap();
+
+    // 10块缩短到2块
+    disk.truncate(handle, 600).unwrap();
+    assert_eq!(disk.virtual_disk.free_block_count(), free_blocks + 8);
+    assert_eq!(disk.read_file_by_name("/f").unwrap(), sample(1, 600));
+
+    // 空文件也占一块
+    disk.truncate(handle, 0).unwrap();
+    assert_eq!(disk.virtual_disk.free_block_count(), free_blocks + 9);
+    assert_eq!(disk.read_file_by_name("/f").unwrap(), b"");
+
+    // 再变长时补0
+    disk.truncate(handle, 1000).unwrap();
+    assert_eq!(disk.virtual_disk.free_block_count(), free_blocks + 8);
+    assert_eq!(disk.read_file_by_name("/f").unwrap(), vec![0u8; 1000]);
+    assert_consistent(&mut disk);
+}
+
+#[test]
+fn large_write_is_split_by_journal_capacity() {
+    let geometry: Geometry = Geometry::new(512, 512).unwrap().with_journal_blocks(16).unwrap();
+    let mut disk = DiskInfo::format(geometry).unwrap();
+    disk.create_file_with_data("/f", &sample(1, 40 * 512)).unwrap();
+    let handle = disk.open_file("/f", OpenFlags::READ_WRITE).unwrap();
+
+    // 覆写40块已有的数据，一个事务放不下
+    let data: Vec<u8> = sample(2, 40 * 512);
+    let result = disk.transaction(|disk| disk.write_at_in_transaction(handle, 0, &data));
+    assert!(matches!(result, Err(FsError::TransactionTooLarge(_))), "{:?}", result);
+    assert_eq!(disk.read_file_by_name("/f").unwrap(), sample(1, 40 * 512));
+
+    // write_at按日志区的容量分成多个事务写入
+    assert_eq!(disk.write_at(handle, 0, &data).unwrap(), data.len());
+    assert_eq!(disk.read_file_by_name("/f").unwrap(), data);
+    assert_consistent(&mut disk);
+}
+
+#[test]
+fn open_handle_follows_defragmented_blocks() {
+    let mut disk = fragmented_disk();
+    let handle = disk.open_file("/big", OpenFlags::READ_WRITE).unwrap();
+    let first_block: usize = disk.resolve_fcb("/big").unwrap().1.first_block;
+    disk.defragment(|_| true).unwrap();
+    assert_ne!(disk.resolve_fcb("/big").unwrap().1.first_block, first_block);
+
+    let mut buf: Vec<u8> = vec![0u8; 5000];
+    assert_eq!(disk.read_at(handle, 0, &mut buf).unwrap(), 5000);
+    assert_eq!(buf, sample(99, 5000));
+    assert_eq!(disk.write_at(handle, 4990, b"moved").unwrap(), 5);
+    assert_eq!(&disk.read_file_by_name("/big").unwrap()[4990..4995], b"moved");
+    disk.close(handle).unwrap();
+    assert_consistent(&mut disk);
+}
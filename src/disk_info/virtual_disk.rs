@@ -1,106 +1,430 @@
-use std::mem::size_of;
+use std::collections::BTreeSet;
+use log::info;
 use serde::{Deserialize, Serialize};
+use super::block_device::{BlockDevice, MemoryDevice};
+use super::error::{FsError, FsResult};
+use super::free_space::FreeBitmap;
+use super::image::{
+    block_checksum, decode_checksums, decode_fat, encode_checksums, encode_fat, Geometry, SuperBlock, CHECKSUM_SIZE,
+    FAT_ENTRY_SIZE,
+};
+use super::journal::{journal_capacity, replay_journal, write_journaled, Transaction};
+use super::ROOT_BLOCK;
 
-pub const BLOCK_COUNT: usize = 1000;        // 块数量
-pub const BLOCK_SIZE: usize = 1024 * 4;     // 块大小：4KB
+pub const BLOCK_COUNT: usize = 1000;        // 默认块数量
+pub const BLOCK_SIZE: usize = 1024 * 4;     // 默认块大小：4KB
 
-pub const EOF_BYTE: u8 = 255;
 
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum FatStatus {
     UnUsed,           // 未使用的块
     NextBlock(usize), // 下一块块号
     EOF,              // 结束标志
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct VirtualDisk {
-    pub fat: Vec<FatStatus>,
-    data: Vec<u8>,
+pub struct VirtualDisk<D: BlockDevice = MemoryDevice> {
+    fat: Vec<FatStatus>,
+    // 空闲块位图，修改FAT时同步更新
+    free_space: FreeBitmap,
+    device: D,
+    super_block: SuperBlock,
+    // 正在进行的事务，没有事务时直接写入块设备
+    transaction: Option<Transaction>,
+    // 修改过、还没有写入FAT区的FAT区块（从0开始编号）
+    dirty_fat: BTreeSet<usize>,
+    // 每个数据块的校验和，镜像没有校验和区时为空
+    checksums: Vec<u32>,
+    // 修改过、还没有写入校验和区的校验和区块（从0开始编号）
+    dirty_checksums: BTreeSet<usize>,
+}
+
+impl Default for VirtualDisk<MemoryDevice> {
+    fn default() -> VirtualDisk<MemoryDevice> {
+        VirtualDisk::new()
+    }
+}
+
+impl VirtualDisk<MemoryDevice> {
+    // 按默认磁盘参数创建数据区在内存中的虚拟磁盘
+    pub fn new() -> VirtualDisk<MemoryDevice> {
+        // 内存块设备的大小正好合适，不会失败
+        VirtualDisk::with_geometry(Geometry::default()).unwrap()
+    }
+
+    // 按给定的磁盘参数创建数据区在内存中的虚拟磁盘
+    pub fn with_geometry(geometry: Geometry) -> FsResult<VirtualDisk<MemoryDevice>> {
+        let device: MemoryDevice = MemoryDevice::new(geometry.block_size, geometry.device_block_count());
+        VirtualDisk::format(device, geometry)
+    }
+
+    // 从镜像文件的数据恢复虚拟磁盘，数据全部放在内存中
+    pub fn from_image(image: &[u8]) -> FsResult<VirtualDisk<MemoryDevice>> {
+        let super_block: SuperBlock = SuperBlock::from_bytes(image)?;
+        VirtualDisk::open(MemoryDevice::from_bytes(image.to_vec(), super_block.block_size as usize))
+    }
 }
 
-impl VirtualDisk {
-    pub fn new() -> VirtualDisk {
-        VirtualDisk {
-            // FAT
-            fat: vec![FatStatus::UnUsed; BLOCK_COUNT],
-            // 数据区
-            data: vec![
-                0u8;
-                (BLOCK_COUNT - size_of::<FatStatus>() * BLOCK_COUNT / BLOCK_SIZE - 1)
-                    * BLOCK_SIZE
-            ],
+impl<D: BlockDevice> VirtualDisk<D> {
+    // 在块设备上按磁盘参数创建空的虚拟磁盘，写入超级块、FAT区和校验和区
+    pub fn format(device: D, geometry: Geometry) -> FsResult<VirtualDisk<D>> {
+        let geometry: Geometry =
+            Geometry::new(geometry.block_size, geometry.block_count)?.with_journal_blocks(geometry.journal_blocks)?;
+        let super_block: SuperBlock = geometry.super_block();
+        VirtualDisk::check_device(&device, &super_block)?;
+        let fat: Vec<FatStatus> = vec![FatStatus::UnUsed; geometry.block_count];
+        let mut disk: VirtualDisk<D> = VirtualDisk {
+            free_space: FreeBitmap::from_fat(&fat),
+            fat,
+            device,
+            super_block,
+            transaction: None,
+            dirty_fat: BTreeSet::new(),
+            // 未使用的块的校验和没有意义，写入数据时才计算
+            checksums: vec![0; geometry.block_count],
+            dirty_checksums: BTreeSet::new(),
+        };
+        let header: Vec<u8> = disk.super_block.to_bytes();
+        disk.device.write_block(0, &header)?;
+        // 块设备上可能留有以前的日志，清空日志头
+        if let Some(journal_start) = disk.super_block.journal_range().next() {
+            let empty: Vec<u8> = vec![0u8; disk.block_size()];
+            disk.device.write_block(journal_start, &empty)?;
+        }
+        // 新的FAT区和校验和区不需要经过日志，直接写入
+        let fat_blocks: Vec<(usize, Vec<u8>)> = (0..VirtualDisk::<D>::fat_blocks(&disk.super_block).len())
+            .map(|index| disk.encode_fat_block(index))
+            .chain((0..disk.super_block.checksum_range().len()).map(|index| disk.encode_checksum_block(index)))
+            .collect();
+        for (block, data) in &fat_blocks {
+            disk.device.write_block(*block, data)?;
         }
+        disk.device.flush()?;
+        Ok(disk)
     }
 
-    // 向disk中的data插入数据。插入数据将覆写相应的位置。
-    pub fn insert_data_by_block(&mut self, data: &[u8], block: usize) {
-        self.insert_data_by_offset(data, block * BLOCK_SIZE);
+    // 打开块设备上已有的虚拟磁盘，只读入超级块和FAT，数据块在需要时再从块设备读取
+    // 打开前先重新写入日志中已经提交的事务
+    pub fn open(mut device: D) -> FsResult<VirtualDisk<D>> {
+        if device.block_count() == 0 {
+            return Err(FsError::InvalidImage(String::from("image is too small")));
+        }
+        let super_block: SuperBlock = SuperBlock::from_bytes(&device.read_block(0)?)?;
+        if super_block.root_block as usize != ROOT_BLOCK {
+            return Err(FsError::InvalidImage(String::from("unsupported root block")));
+        }
+        VirtualDisk::check_device(&device, &super_block)?;
+        let replayed: usize = replay_journal(&mut device, &super_block)?;
+        if replayed > 0 {
+            info!("Replayed {} blocks from the journal", replayed);
+        }
+
+        let mut fat_data: Vec<u8> = Vec::with_capacity(super_block.data_offset as usize);
+        for block in VirtualDisk::<D>::fat_blocks(&super_block) {
+            fat_data.append(&mut device.read_block(block)?);
+        }
+        let fat: Vec<FatStatus> = decode_fat(&fat_data, super_block.block_count as usize)?;
+
+        let mut checksum_data: Vec<u8> = Vec::new();
+        for block in super_block.checksum_range() {
+            checksum_data.append(&mut device.read_block(block)?);
+        }
+        let checksums: Vec<u32> = decode_checksums(&checksum_data, super_block.block_count as usize);
+
+        let free_space: FreeBitmap = FreeBitmap::from_fat(&fat);
+        Ok(VirtualDisk {
+            fat,
+            free_space,
+            device,
+            super_block,
+            transaction: None,
+            dirty_fat: BTreeSet::new(),
+            checksums,
+            dirty_checksums: BTreeSet::new(),
+        })
     }
 
-    // 向disk的data中插入数据。插入的数据将覆写相应位置的数据。
-    pub fn insert_data_by_offset(&mut self, data: &[u8], offset: usize) {
-        self.data
-            .splice(offset..(offset + data.len()), data.iter().cloned());
+    // 检查块设备是否能放下整个镜像
+    fn check_device(device: &D, super_block: &SuperBlock) -> FsResult<()> {
+        if device.block_size() != super_block.block_size as usize {
+            return Err(FsError::InvalidImage(String::from("block size does not match the device")));
+        }
+        if (device.block_count() as u64) * (device.block_size() as u64) < super_block.image_len() {
+            return Err(FsError::InvalidImage(String::from("image is truncated")));
+        }
+        Ok(())
     }
 
+    // FAT区在块设备上占用的块
+    fn fat_blocks(super_block: &SuperBlock) -> std::ops::Range<usize> {
+        let block_size: u64 = super_block.block_size as u64;
+        (super_block.fat_offset / block_size) as usize..(super_block.checksum_offset / block_size) as usize
+    }
+
+    // 数据区的块号对应的块设备块号
+    fn device_block(&self, block: usize) -> usize {
+        (self.super_block.data_offset / self.super_block.block_size as u64) as usize + block
+    }
+
+    // 底层的块设备
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    // 磁盘参数
+    pub fn geometry(&self) -> Geometry {
+        self.super_block.geometry()
+    }
+
+    // 块大小
+    pub fn block_size(&self) -> usize {
+        self.super_block.block_size as usize
+    }
+
+    // FAT
+    pub fn fat(&self) -> &[FatStatus] {
+        &self.fat
+    }
+
+    // 修改FAT中的一项，同时更新空闲块位图
+    pub fn set_fat(&mut self, block: usize, status: FatStatus) {
+        if let Some(transaction) = &mut self.transaction {
+            transaction.fat_original.entry(block).or_insert_with(|| self.fat[block].clone());
+        }
+        self.dirty_fat.insert(block * FAT_ENTRY_SIZE / self.block_size());
+        self.update_fat(block, status);
+    }
+
+    // 修改FAT中的一项和空闲块位图，不记录到事务中
+    fn update_fat(&mut self, block: usize, status: FatStatus) {
+        match status {
+            FatStatus::UnUsed => self.free_space.mark_free(block),
+            _ => self.free_space.mark_used(block),
+        }
+        self.fat[block] = status;
+    }
+
+    // 是否记录并检查数据块的校验和，旧版本的镜像没有校验和区
+    pub fn has_checksums(&self) -> bool {
+        self.super_block.has_checksums()
+    }
+
+    // 更新一个数据块的校验和，block_data是这一块完整的新数据
+    fn update_checksum(&mut self, block: usize, block_data: &[u8]) {
+        if !self.has_checksums() {
+            return;
+        }
+        if let Some(transaction) = &mut self.transaction {
+            transaction.checksum_original.entry(block).or_insert(self.checksums[block]);
+        }
+        self.dirty_checksums.insert(block * CHECKSUM_SIZE / self.block_size());
+        self.checksums[block] = block_checksum(block_data);
+    }
+
+    // 检查一个数据块的校验和，未使用的块不检查
+    fn verify_checksum(&self, block: usize, block_data: &[u8]) -> FsResult<()> {
+        if !self.has_checksums() || matches!(self.fat[block], FatStatus::UnUsed) {
+            return Ok(());
+        }
+        if block_checksum(block_data) != self.checksums[block] {
+            return Err(FsError::BadChecksum(String::new(), block));
+        }
+        Ok(())
+    }
+
+    // 一个事务最多能修改的块数，没有日志区时为None，不受限制
+    pub fn journal_capacity(&self) -> Option<usize> {
+        match self.super_block.journal_blocks {
+            0 => None,
+            _ => Some(journal_capacity(&self.super_block)),
+        }
+    }
+
+    // 块号最小的空闲块
+    pub fn first_free_block(&self) -> Option<usize> {
+        self.free_space.first_free()
+    }
+
+    // 空闲块数量
+    pub fn free_block_count(&self) -> usize {
+        self.free_space.free_count()
+    }
+
+    // 空闲块位图
+    pub fn free_space(&self) -> &FreeBitmap {
+        &self.free_space
+    }
+
+    // 将修改过的FAT通过日志写入FAT区，并把块设备缓存中的数据写到底层存储上
+    // 事务进行中时不做任何事，等事务提交时一起写入
+    pub fn flush(&mut self) -> FsResult<()> {
+        if self.transaction.is_some() {
+            return Ok(());
+        }
+        self.write_pending(Vec::new())
+    }
+
+    // FAT区中的第index块的数据，返回块设备块号和数据
+    fn encode_fat_block(&self, index: usize) -> (usize, Vec<u8>) {
+        let entries: usize = self.block_size() / FAT_ENTRY_SIZE;
+        let start: usize = (index * entries).min(self.fat.len());
+        let end: usize = ((index + 1) * entries).min(self.fat.len());
+        let mut data: Vec<u8> = encode_fat(&self.fat[start..end]);
+        data.resize(self.block_size(), 0);
+        (VirtualDisk::<D>::fat_blocks(&self.super_block).start + index, data)
+    }
+
+    // 校验和区中的第index块的数据，返回块设备块号和数据
+    fn encode_checksum_block(&self, index: usize) -> (usize, Vec<u8>) {
+        let entries: usize = self.block_size() / CHECKSUM_SIZE;
+        let start: usize = (index * entries).min(self.checksums.len());
+        let end: usize = ((index + 1) * entries).min(self.checksums.len());
+        let mut data: Vec<u8> = encode_checksums(&self.checksums[start..end]);
+        data.resize(self.block_size(), 0);
+        (self.super_block.checksum_range().start + index, data)
+    }
+
+    // 把writes和修改过的FAT区块、校验和区块一起通过日志写入
+    fn write_pending(&mut self, mut writes: Vec<(usize, Vec<u8>)>) -> FsResult<()> {
+        writes.extend(self.dirty_fat.iter().map(|index| self.encode_fat_block(*index)));
+        writes.extend(self.dirty_checksums.iter().map(|index| self.encode_checksum_block(*index)));
+        write_journaled(&mut self.device, &self.super_block, &writes)?;
+        self.dirty_fat.clear();
+        self.dirty_checksums.clear();
+        Ok(())
+    }
+
+    /// 开始一个事务：之后对已使用的块的覆写先保存在内存中，提交时和FAT一起通过日志写入，
+    /// 写入事务开始时空闲的块不会破坏已有的数据，仍然直接写入
+    /// 已经在事务中时返回false，这时由外层的事务负责提交或撤销
+    pub fn begin_transaction(&mut self) -> bool {
+        if self.transaction.is_some() {
+            return false;
+        }
+        self.transaction = Some(Transaction::default());
+        true
+    }
+
+    /// 提交事务。失败时事务仍然存在，可以撤销
+    pub fn commit_transaction(&mut self) -> FsResult<()> {
+        let writes: Vec<(usize, Vec<u8>)> = match &self.transaction {
+            Some(transaction) => transaction.writes.iter().map(|(block, data)| (*block, data.clone())).collect(),
+            None => return Ok(()),
+        };
+        self.write_pending(writes)?;
+        self.transaction = None;
+        Ok(())
+    }
+
+    /// 撤销事务：恢复修改过的FAT项和校验和，丢弃还没有写入的块
+    pub fn rollback_transaction(&mut self) {
+        if let Some(transaction) = self.transaction.take() {
+            for (block, status) in transaction.fat_original {
+                self.update_fat(block, status);
+            }
+            for (block, checksum) in transaction.checksum_original {
+                self.checksums[block] = checksum;
+            }
+        }
+    }
+
+    // 生成镜像文件的全部数据：超级块、FAT区、校验和区、数据区
+    pub fn to_image(&self) -> FsResult<Vec<u8>> {
+        let mut image: Vec<u8> = Vec::with_capacity(self.super_block.image_len() as usize);
+        image.append(&mut self.super_block.to_bytes());
+        image.append(&mut encode_fat(&self.fat));
+        image.resize(self.super_block.checksum_offset as usize, 0);
+        image.append(&mut encode_checksums(&self.checksums));
+        image.resize(self.super_block.data_offset as usize, 0);
+        for block in 0..self.fat.len() {
+            image.append(&mut self.read_data_by_block(block)?);
+        }
+        Ok(image)
+    }
+
+    // 事务中保存在内存中、还没有写入块设备的块
+    fn staged_block(&self, device_block: usize) -> Option<&Vec<u8>> {
+        self.transaction.as_ref().and_then(|transaction| transaction.writes.get(&device_block))
+    }
 
     // 向disk中的data插入数据。插入数据将覆写相应的位置。
-    pub fn write_data_by_blocks_with_eof(
-        &mut self,
-        data: &[u8],
-        blocks: &[usize],
-        insert_eof: bool,
-    ) {
+    pub fn insert_data_by_block(&mut self, data: &[u8], block: usize) -> FsResult<()> {
+        if block >= self.fat.len() {
+            return Err(FsError::CorruptChain(block));
+        }
+        let device_block: usize = self.device_block(block);
+        // 不足一块时和原来的数据合并成完整的一块，这样才能计算校验和
+        let buffer: Vec<u8> = if data.len() < self.block_size() {
+            let mut buffer: Vec<u8> = match self.staged_block(device_block) {
+                Some(buffer) => buffer.clone(),
+                None => self.device.read_block(device_block)?,
+            };
+            buffer[..data.len()].copy_from_slice(data);
+            buffer
+        } else if data.len() == self.block_size() {
+            data.to_vec()
+        } else {
+            return Err(FsError::CorruptChain(block));
+        };
+        self.update_checksum(block, &buffer);
+        match &mut self.transaction {
+            // 覆写事务开始前已经在使用的块，先保存在内存中，提交时通过日志写入
+            // 已经保存在内存中的块之后的写入也要合并进去，否则读出和提交的都是旧数据
+            Some(transaction) if !transaction.is_fresh(block) || transaction.writes.contains_key(&device_block) => {
+                transaction.writes.insert(device_block, buffer);
+                Ok(())
+            }
+            _ => self.device.write_block(device_block, &buffer),
+        }
+    }
+
+
+    // 向disk中的data插入数据。插入数据将覆写相应的位置。
+    // 数据的长度记录在FCB中，最后一块中数据之后的部分用0填充
+    pub fn write_data_by_blocks(&mut self, data: &[u8], blocks: &[usize]) -> FsResult<()> {
+        let block_size: usize = self.block_size();
         for i in 0..blocks.len() {
             if i < blocks.len() - 1 {
-                // 正常分BLOCK_SIZE写入块
+                // 正常分block_size写入块
                 self.insert_data_by_block(
-                    &data[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE],
+                    &data[i * block_size..(i + 1) * block_size],
                     blocks[i],
-                );
+                )?;
             } else {
-                // 开始写入最后一个块
-                let mut buffer: Vec<u8> = Vec::with_capacity(BLOCK_SIZE);
-                buffer.extend((&data[i * BLOCK_SIZE..data.len()]).iter());
-                if insert_eof {
-                    // 插入EoF
-                    buffer.push(EOF_BYTE);
-                }
-                if buffer.len() < BLOCK_SIZE {
-                    // 若未到 BLOCK_SIZE 则用0填充
-                    let mut zero = vec![0u8; BLOCK_SIZE - buffer.len()];
-                    buffer.append(&mut zero);
-                }
-                self.insert_data_by_block(buffer.as_slice(), blocks[i])
+                // 开始写入最后一个块，若未到 block_size 则用0填充
+                let mut buffer: Vec<u8> = Vec::with_capacity(block_size);
+                buffer.extend(data[i * block_size..].iter());
+                buffer.resize(block_size, 0);
+                self.insert_data_by_block(buffer.as_slice(), blocks[i])?;
             }
         }
+        Ok(())
     }
 
     // 从disk中读取数据。
-    pub fn read_data_by_block(&self, block: usize) -> Vec<u8> {
-        (&self.data[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE]).to_vec()
+    pub fn read_data_by_block(&self, block: usize) -> FsResult<Vec<u8>> {
+        if block >= self.fat.len() {
+            return Err(FsError::CorruptChain(block));
+        }
+        let device_block: usize = self.device_block(block);
+        let data: Vec<u8> = match self.staged_block(device_block) {
+            Some(data) => data.clone(),
+            None => self.device.read_block(device_block)?,
+        };
+        self.verify_checksum(block, &data)?;
+        Ok(data)
     }
 
-    // 根据给出的块号，读出所有数据，并且检测EoF。
-    pub fn read_data_by_blocks_without_eof(&self, blocks: &[usize]) -> Vec<u8> {
-        let mut data: Vec<u8> = Vec::with_capacity(blocks.len() * BLOCK_SIZE);
+    // 根据给出的块号，读出所有块的完整数据，由调用者按FCB中记录的长度截断
+    pub fn read_data_by_blocks(&self, blocks: &[usize]) -> FsResult<Vec<u8>> {
+        let block_size: usize = self.block_size();
+        let mut data: Vec<u8> = Vec::with_capacity(blocks.len() * block_size);
 
         // 循环读出所有数据
         for block in blocks {
-            let mut buffer: Vec<u8> = self.read_data_by_block(*block);
+            let mut buffer: Vec<u8> = self.read_data_by_block(*block)?;
             data.append(&mut buffer);
         }
-        // 从后向前查找，从EoF开始截断。若未找到EoF则直接返回。
-        for i in 1..BLOCK_SIZE {
-            let index = data.len() - i;
-            if data[index] == EOF_BYTE {
-                // 不加不减，刚好将EoF截断在外
-                data.truncate(index);
-                break;
-            }
-        }
-        data
+        Ok(data)
     }
 }
@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use super::block_device::BlockDevice;
+use super::error::FsResult;
+use super::file_handle::{FileHandle, OpenFlags};
+use super::DiskInfo;
+
+/// 访问DiskInfo的方式：VirtualFile可以借用DiskInfo，也可以和其他VirtualFile共享同一个DiskInfo
+pub trait DiskAccess {
+    /// DiskInfo使用的块设备
+    type Device: BlockDevice;
+    /// 在op中访问DiskInfo
+    fn with_disk<T, F: FnOnce(&mut DiskInfo<Self::Device>) -> T>(&mut self, op: F) -> T;
+}
+
+impl<D: BlockDevice> DiskAccess for &mut DiskInfo<D> {
+    type Device = D;
+
+    fn with_disk<T, F: FnOnce(&mut DiskInfo<D>) -> T>(&mut self, op: F) -> T {
+        op(self)
+    }
+}
+
+impl<D: BlockDevice> DiskAccess for Rc<RefCell<DiskInfo<D>>> {
+    type Device = D;
+
+    fn with_disk<T, F: FnOnce(&mut DiskInfo<D>) -> T>(&mut self, op: F) -> T {
+        op(&mut self.borrow_mut())
+    }
+}
+
+impl<D: BlockDevice> DiskAccess for Arc<Mutex<DiskInfo<D>>> {
+    type Device = D;
+
+    fn with_disk<T, F: FnOnce(&mut DiskInfo<D>) -> T>(&mut self, op: F) -> T {
+        // 其他线程持有锁时出错不影响DiskInfo的一致性，每个操作都是一个事务
+        op(&mut self.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+}
+
+/// 虚拟磁盘中打开的文件，实现了std::io的Read、Write、Seek和BufRead
+/// 读取时每次读入一整块缓存起来，drop时关闭文件句柄
+pub struct VirtualFile<A: DiskAccess> {
+    disk: A,
+    handle: FileHandle,
+    flags: OpenFlags,
+    // 当前读写位置
+    position: u64,
+    // 读缓存，保存从buffer_start开始的一块数据，写入时清空
+    buffer: Vec<u8>,
+    buffer_start: u64,
+    block_size: usize,
+}
+
+impl<A: DiskAccess> VirtualFile<A> {
+    /// 打开文件，path可以是绝对路径或相对路径
+    pub fn open(mut disk: A, path: &str, flags: OpenFlags) -> FsResult<VirtualFile<A>> {
+        let (handle, block_size) = disk.with_disk(|disk| {
+            disk.open_file(path, flags)
+                .map(|handle| (handle, disk.virtual_disk.block_size()))
+        })?;
+        Ok(VirtualFile {
+            disk,
+            handle,
+            flags,
+            position: 0,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            block_size,
+        })
+    }
+
+    /// 文件句柄
+    pub fn handle(&self) -> FileHandle {
+        self.handle
+    }
+
+    /// 文件当前的长度
+    pub fn length(&mut self) -> FsResult<u64> {
+        let handle: FileHandle = self.handle;
+        self.disk.with_disk(|disk| disk.file_length(handle))
+    }
+
+    /// 修改文件的长度，读写位置不变
+    pub fn set_length(&mut self, length: u64) -> FsResult<()> {
+        let handle: FileHandle = self.handle;
+        self.buffer.clear();
+        self.disk.with_disk(|disk| disk.truncate(handle, length))
+    }
+
+    // 缓存中从当前读写位置开始的数据，当前位置不在缓存中时为空
+    fn buffered(&self) -> &[u8] {
+        let buffer_end: u64 = self.buffer_start + self.buffer.len() as u64;
+        if self.position < self.buffer_start || self.position >= buffer_end {
+            return &[];
+        }
+        &self.buffer[(self.position - self.buffer_start) as usize..]
+    }
+}
+
+impl<A: DiskAccess> Read for VirtualFile<A> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // 缓存中没有数据且要读的不少于一块时直接读取，不经过缓存
+        if self.buffered().is_empty() && buf.len() >= self.block_size {
+            let (handle, position) = (self.handle, self.position);
+            let count: usize = self.disk.with_disk(|disk| disk.read_at(handle, position, buf))?;
+            self.position += count as u64;
+            return Ok(count);
+        }
+        let available: &[u8] = self.fill_buf()?;
+        let count: usize = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+impl<A: DiskAccess> BufRead for VirtualFile<A> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buffered().is_empty() {
+            // 读入当前位置所在的整块
+            let handle: FileHandle = self.handle;
+            let block_start: u64 = self.position - self.position % self.block_size as u64;
+            let mut buffer: Vec<u8> = std::mem::take(&mut self.buffer);
+            buffer.resize(self.block_size, 0);
+            let count: usize = self.disk.with_disk(|disk| disk.read_at(handle, block_start, &mut buffer))?;
+            buffer.truncate(count);
+            self.buffer = buffer;
+            self.buffer_start = block_start;
+        }
+        Ok(self.buffered())
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position += amount.min(self.buffered().len()) as u64;
+    }
+}
+
+impl<A: DiskAccess> Write for VirtualFile<A> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let handle: FileHandle = self.handle;
+        self.buffer.clear();
+        if self.flags.append {
+            self.position = self.length()?;
+        }
+        let position: u64 = self.position;
+        let count: usize = self.disk.with_disk(|disk| disk.write_at(handle, position, data))?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // 每次写入都在事务中完成，这里只需要把块设备的缓存写到底层存储上
+        self.disk.with_disk(|disk| disk.flush())?;
+        Ok(())
+    }
+}
+
+impl<A: DiskAccess> Seek for VirtualFile<A> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (handle, position) = (self.handle, self.position);
+        // 缓存以文件中的位置为准，移动读写位置后仍然有效
+        self.position = self.disk.with_disk(|disk| {
+            disk.seek(handle, SeekFrom::Start(position))?;
+            disk.seek(handle, pos)
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl<A: DiskAccess> Drop for VirtualFile<A> {
+    fn drop(&mut self) {
+        let handle: FileHandle = self.handle;
+        // 文件已经被删除时句柄已经失效，忽略错误
+        let _ = self.disk.with_disk(|disk| disk.close(handle));
+    }
+}
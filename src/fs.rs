@@ -0,0 +1,390 @@
+//! 和`std::fs`相同接口的文件操作，读写的是当前线程挂载的虚拟磁盘而不是宿主机的文件系统
+//!
+//! 把`use std::fs;`换成`use rust_file_system::fs;`，使用`fs::read`、`fs::write`、`fs::File`等的代码
+//! 就会在内存中的虚拟磁盘上运行。每个线程有自己的虚拟磁盘，第一次使用时自动创建空的文件系统，
+//! 也可以用`mount`换成已有的文件系统。出错时`FsError`转换为对应类型的`io::Error`。
+//!
+//! ```
+//! use rust_file_system::fs;
+//! use std::io::Write;
+//!
+//! fs::create_dir_all("/data/logs")?;
+//! fs::write("/data/config.toml", "debug = true")?;
+//! assert_eq!(fs::read_to_string("/data/config.toml")?, "debug = true");
+//!
+//! // 以追加方式打开时，每次都写在文件末尾
+//! for message in ["started", "stopped"] {
+//!     let mut log = fs::OpenOptions::new().append(true).create(true).open("/data/logs/app.log")?;
+//!     writeln!(log, "{}", message)?;
+//! }
+//! assert_eq!(fs::read_to_string("/data/logs/app.log")?, "started\nstopped\n");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+#[cfg(test)]
+mod tests;
+
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::vec;
+use crate::disk_info::error::{FsError, FsResult};
+use crate::disk_info::file_handle::OpenFlags;
+use crate::disk_info::virtual_file::VirtualFile;
+use crate::disk_info::{Directory, DiskInfo, Fcb, FileType};
+
+thread_local! {
+    // 当前线程挂载的虚拟磁盘
+    static MOUNTED: RefCell<Rc<RefCell<DiskInfo>>> = RefCell::new(Rc::new(RefCell::new(DiskInfo::new(None))));
+}
+
+/// 把disk挂载为当前线程使用的虚拟磁盘，已经打开的File仍然读写原来的虚拟磁盘
+pub fn mount(disk: DiskInfo) {
+    MOUNTED.with(|mounted| *mounted.borrow_mut() = Rc::new(RefCell::new(disk)));
+}
+
+/// 当前线程挂载的虚拟磁盘，可以用来保存镜像或者直接调用DiskInfo的方法
+pub fn mounted() -> Rc<RefCell<DiskInfo>> {
+    MOUNTED.with(|mounted| mounted.borrow().clone())
+}
+
+// 在当前线程挂载的虚拟磁盘上执行op
+fn with_disk<T, F: FnOnce(&mut DiskInfo) -> FsResult<T>>(op: F) -> io::Result<T> {
+    Ok(op(&mut mounted().borrow_mut())?)
+}
+
+// 虚拟磁盘中的路径只能是UTF-8字符串
+fn path_str(path: &Path) -> io::Result<&str> {
+    path.to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidFilename, format!("{:?}: Path is not valid UTF-8", path)))
+}
+
+/// 创建目录，父目录必须已经存在
+pub fn create_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path: &str = path_str(path.as_ref())?;
+    with_disk(|disk| disk.new_directory_to_disk(path))
+}
+
+/// 创建目录以及所有不存在的上级目录，目录已经存在时什么也不做
+pub fn create_dir_all<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path: &str = path_str(path.as_ref())?;
+    let mut prefix: String = String::from(if path.starts_with('/') { "/" } else { "" });
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        prefix.push_str(name);
+        with_disk(|disk| match disk.get_fcb_by_path(&prefix) {
+            Ok(fcb) if fcb.file_type().is_dir() => Ok(()),
+            Ok(_) => Err(FsError::NotADirectory(prefix.clone())),
+            Err(FsError::NotFound(_)) => disk.new_directory_to_disk(&prefix),
+            Err(err) => Err(err),
+        })?;
+        prefix.push('/');
+    }
+    Ok(())
+}
+
+/// 读出文件的全部数据
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let path: &str = path_str(path.as_ref())?;
+    with_disk(|disk| disk.read_file_by_name(path))
+}
+
+/// 读出文件的全部数据，数据必须是UTF-8字符串
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    String::from_utf8(read(path)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// 把contents写入文件，文件不存在时创建，已经存在时替换原来的内容
+pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
+    File::create(path)?.write_all(contents.as_ref())
+}
+
+/// 删除文件
+pub fn remove_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path: &str = path_str(path.as_ref())?;
+    with_disk(|disk| {
+        if disk.get_fcb_by_path(path)?.file_type().is_dir() {
+            return Err(FsError::IsADirectory(String::from(path)));
+        }
+        disk.delete_file_by_name(path)
+    })
+}
+
+/// 删除空目录
+pub fn remove_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path: &str = path_str(path.as_ref())?;
+    with_disk(|disk| {
+        if disk.get_fcb_by_path(path)?.file_type().is_file() {
+            return Err(FsError::NotADirectory(String::from(path)));
+        }
+        disk.delete_file_by_name(path)
+    })
+}
+
+/// 删除目录以及其中的所有文件和子目录
+pub fn remove_dir_all<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path: &str = path_str(path.as_ref())?;
+    with_disk(|disk| {
+        if disk.get_fcb_by_path(path)?.file_type().is_file() {
+            return Err(FsError::NotADirectory(String::from(path)));
+        }
+        disk.delete_file_by_name_recursively(path)
+    })
+}
+
+/// 改名或移动文件和目录，to是已经存在的文件时替换掉它
+pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    let from: &str = path_str(from.as_ref())?;
+    let to: &str = path_str(to.as_ref())?;
+    with_disk(|disk| disk.replace_file_by_name(from, to))
+}
+
+/// 复制文件的内容，to已经存在时替换原来的内容，返回复制的字节数
+pub fn copy<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<u64> {
+    let data: Vec<u8> = read(from)?;
+    write(to, &data)?;
+    Ok(data.len() as u64)
+}
+
+/// 文件或目录的信息
+pub fn metadata<P: AsRef<Path>>(path: P) -> io::Result<Metadata> {
+    let path: &str = path_str(path.as_ref())?;
+    with_disk(|disk| disk.get_fcb_by_path(path)).map(|fcb| Metadata::from_fcb(&fcb))
+}
+
+/// 列出目录中的文件和子目录，不包括"."和".."
+pub fn read_dir<P: AsRef<Path>>(path: P) -> io::Result<ReadDir> {
+    let dir_path: &Path = path.as_ref();
+    let path: &str = path_str(dir_path)?;
+    let dir: Directory = with_disk(|disk| disk.get_directory_by_path(path))?;
+    let entries: Vec<DirEntry> = dir.files()[2..]
+        .iter()
+        .map(|fcb| DirEntry {
+            path: dir_path.join(fcb.name()),
+            fcb: fcb.clone(),
+        })
+        .collect();
+    Ok(ReadDir {
+        entries: entries.into_iter(),
+    })
+}
+
+/// 文件或目录的信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    file_type: FileType,
+    len: u64,
+}
+
+impl Metadata {
+    fn from_fcb(fcb: &Fcb) -> Metadata {
+        Metadata {
+            file_type: fcb.file_type(),
+            len: fcb.length() as u64,
+        }
+    }
+
+    /// 文件类型
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// 是否是目录
+    pub fn is_dir(&self) -> bool {
+        self.file_type.is_dir()
+    }
+
+    /// 是否是文件
+    pub fn is_file(&self) -> bool {
+        self.file_type.is_file()
+    }
+
+    /// 长度，单位为字节
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// 长度是否为0
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// read_dir返回的迭代器
+#[derive(Debug)]
+pub struct ReadDir {
+    entries: vec::IntoIter<DirEntry>,
+}
+
+impl Iterator for ReadDir {
+    type Item = io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<io::Result<DirEntry>> {
+        self.entries.next().map(Ok)
+    }
+}
+
+/// 目录中的一项
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    path: PathBuf,
+    fcb: Fcb,
+}
+
+impl DirEntry {
+    /// 完整路径，即read_dir的参数后面加上文件名
+    pub fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+
+    /// 文件名
+    pub fn file_name(&self) -> OsString {
+        OsString::from(self.fcb.name())
+    }
+
+    /// 文件或目录的信息
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        Ok(Metadata::from_fcb(&self.fcb))
+    }
+
+    /// 文件类型
+    pub fn file_type(&self) -> io::Result<FileType> {
+        Ok(self.fcb.file_type())
+    }
+}
+
+/// 打开文件的选项，和std::fs::OpenOptions相同
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    flags: OpenFlags,
+    create_new: bool,
+}
+
+impl OpenOptions {
+    /// 所有选项都为false
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    /// 可以读取
+    pub fn read(&mut self, read: bool) -> &mut OpenOptions {
+        self.flags.read = read;
+        self
+    }
+
+    /// 可以写入
+    pub fn write(&mut self, write: bool) -> &mut OpenOptions {
+        self.flags.write = write;
+        self
+    }
+
+    /// 每次写入前都移动到文件末尾，同时表示可以写入
+    pub fn append(&mut self, append: bool) -> &mut OpenOptions {
+        self.flags.append = append;
+        self
+    }
+
+    /// 打开时把文件长度截断为0
+    pub fn truncate(&mut self, truncate: bool) -> &mut OpenOptions {
+        self.flags.truncate = truncate;
+        self
+    }
+
+    /// 文件不存在时创建空文件
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.flags.create = create;
+        self
+    }
+
+    /// 创建新文件，文件已经存在时返回错误
+    pub fn create_new(&mut self, create_new: bool) -> &mut OpenOptions {
+        self.create_new = create_new;
+        self
+    }
+
+    /// 按选项打开文件
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> io::Result<File> {
+        let path: &str = path_str(path.as_ref())?;
+        let mut flags: OpenFlags = self.flags;
+        // 和std一样，追加意味着可以写入
+        flags.write |= flags.append;
+        if self.create_new {
+            if with_disk(|disk| disk.get_fcb_by_path(path)).is_ok() {
+                return Err(FsError::AlreadyExists(String::from(path)).into());
+            }
+            flags.create = true;
+        }
+        Ok(File {
+            inner: RefCell::new(VirtualFile::open(mounted(), path, flags)?),
+        })
+    }
+}
+
+/// 虚拟磁盘中打开的文件，和std::fs::File相同
+pub struct File {
+    // 和std一样，查询和修改长度只需要&self
+    inner: RefCell<VirtualFile<Rc<RefCell<DiskInfo>>>>,
+}
+
+impl File {
+    /// 以只读方式打开文件
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        OpenOptions::new().read(true).open(path)
+    }
+
+    /// 以只写方式打开文件，文件不存在时创建，已经存在时截断为0
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        OpenOptions::new().write(true).create(true).truncate(true).open(path)
+    }
+
+    /// 以读写方式创建新文件，文件已经存在时返回错误
+    pub fn create_new<P: AsRef<Path>>(path: P) -> io::Result<File> {
+        OpenOptions::new().read(true).write(true).create_new(true).open(path)
+    }
+
+    /// 打开文件的选项
+    pub fn options() -> OpenOptions {
+        OpenOptions::new()
+    }
+
+    /// 文件的信息
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        Ok(Metadata {
+            file_type: FileType::File,
+            len: self.inner.borrow_mut().length()?,
+        })
+    }
+
+    /// 修改文件的长度，变长时用0填充
+    pub fn set_len(&self, size: u64) -> io::Result<()> {
+        Ok(self.inner.borrow_mut().set_length(size)?)
+    }
+
+    /// 把修改写到底层存储上
+    pub fn sync_all(&self) -> io::Result<()> {
+        self.inner.borrow_mut().flush()
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.get_mut().read(buf)
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.inner.get_mut().write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.get_mut().flush()
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.get_mut().seek(pos)
+    }
+}
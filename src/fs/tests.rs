@@ -0,0 +1,120 @@
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use crate::disk_info::error::FsError;
+use super::{File, OpenOptions};
+
+// 每个测试在自己的线程中运行，使用各自的虚拟磁盘
+
+// 出错时的io::ErrorKind
+fn kind<T>(result: io::Result<T>) -> ErrorKind {
+    match result {
+        Ok(_) => panic!("operation succeeded"),
+        Err(err) => err.kind(),
+    }
+}
+
+#[test]
+fn create_dir_all_creates_missing_parents() {
+    super::create_dir("/a").unwrap();
+    super::create_dir_all("/a/b/c").unwrap();
+    super::create_dir_all("a/b/d/").unwrap();
+    for path in ["/a", "/a/b", "/a/b/c", "/a/b/d"] {
+        assert!(super::metadata(path).unwrap().is_dir(), "{}", path);
+    }
+
+    // 已经存在时什么也不做，路径中有文件时失败
+    super::write("/a/b/c/f", "data").unwrap();
+    super::create_dir_all("/a/b/c").unwrap();
+    assert_eq!(super::read("/a/b/c/f").unwrap(), b"data");
+    assert_eq!(kind(super::create_dir_all("/a/b/c/f/g")), ErrorKind::NotADirectory);
+    assert_eq!(kind(super::create_dir_all("/a/b/c/f")), ErrorKind::NotADirectory);
+}
+
+#[test]
+fn rename_replaces_an_existing_file() {
+    super::write("/old", "old contents").unwrap();
+    super::write("/new", "new contents").unwrap();
+    super::rename("/new", "/old").unwrap();
+    assert_eq!(super::read_to_string("/old").unwrap(), "new contents");
+    assert_eq!(kind(super::metadata("/new")), ErrorKind::NotFound);
+
+    // 移动到其他目录并替换那里的文件
+    super::create_dir("/dir").unwrap();
+    super::write("/dir/target", "replaced").unwrap();
+    super::rename("/old", "/dir/target").unwrap();
+    assert_eq!(super::read_to_string("/dir/target").unwrap(), "new contents");
+    assert_eq!(super::read_dir("/").unwrap().count(), 1);
+
+    // 目录不会被文件替换
+    super::write("/file", "file").unwrap();
+    assert_eq!(kind(super::rename("/file", "/dir")), ErrorKind::AlreadyExists);
+    assert_eq!(super::read_to_string("/file").unwrap(), "file");
+}
+
+#[test]
+fn append_always_writes_at_the_end() {
+    super::write("/log", "first\n").unwrap();
+    let mut log: File = OpenOptions::new().append(true).open("/log").unwrap();
+    log.write_all(b"second\n").unwrap();
+    // 移动到开头之后仍然写在末尾
+    log.seek(SeekFrom::Start(0)).unwrap();
+    log.write_all(b"third\n").unwrap();
+    drop(log);
+    assert_eq!(super::read_to_string("/log").unwrap(), "first\nsecond\nthird\n");
+
+    // 追加方式打开时不能读，没有create时文件必须存在
+    let mut log: File = OpenOptions::new().append(true).open("/log").unwrap();
+    assert_eq!(kind(log.read(&mut [0u8; 4])), ErrorKind::PermissionDenied);
+    assert_eq!(kind(OpenOptions::new().append(true).open("/missing")), ErrorKind::NotFound);
+    OpenOptions::new().append(true).create(true).open("/missing").unwrap().write_all(b"new").unwrap();
+    assert_eq!(super::read_to_string("/missing").unwrap(), "new");
+}
+
+#[test]
+fn remove_dir_all_removes_the_whole_tree() {
+    super::create_dir_all("/tree/a/b").unwrap();
+    super::create_dir("/tree/c").unwrap();
+    super::write("/tree/f", vec![1u8; 3000]).unwrap();
+    super::write("/tree/a/b/g", "g").unwrap();
+    super::write("/keep", "keep").unwrap();
+    let (_, _, free_before) = super::mounted().borrow().get_disk_info();
+
+    assert_eq!(kind(super::remove_dir("/tree")), ErrorKind::DirectoryNotEmpty);
+    assert_eq!(kind(super::remove_dir_all("/keep")), ErrorKind::NotADirectory);
+    super::remove_dir_all("/tree").unwrap();
+    assert_eq!(kind(super::metadata("/tree")), ErrorKind::NotFound);
+    assert_eq!(kind(super::read("/tree/a/b/g")), ErrorKind::NotFound);
+    assert_eq!(super::read_to_string("/keep").unwrap(), "keep");
+    // 目录树占用的块都被释放
+    let (_, _, free_after) = super::mounted().borrow().get_disk_info();
+    assert!(free_after > free_before);
+    assert_eq!(kind(super::remove_dir_all("/tree")), ErrorKind::NotFound);
+}
+
+#[test]
+fn errors_map_to_io_error_kinds() {
+    super::create_dir("/dir").unwrap();
+    super::write("/dir/file", "data").unwrap();
+    assert_eq!(kind(super::read("/missing")), ErrorKind::NotFound);
+    assert_eq!(kind(super::create_dir("/dir")), ErrorKind::AlreadyExists);
+    assert_eq!(kind(File::create_new("/dir/file")), ErrorKind::AlreadyExists);
+    assert_eq!(kind(super::read("/dir")), ErrorKind::IsADirectory);
+    assert_eq!(kind(super::remove_file("/dir")), ErrorKind::IsADirectory);
+    assert_eq!(kind(super::remove_dir("/dir/file")), ErrorKind::NotADirectory);
+    assert_eq!(kind(super::read_dir("/dir/file")), ErrorKind::NotADirectory);
+    assert_eq!(kind(super::remove_dir("/dir")), ErrorKind::DirectoryNotEmpty);
+    assert_eq!(kind(super::create_dir("/dir/..")), ErrorKind::InvalidFilename);
+    assert_eq!(kind(File::open("/dir/file").unwrap().write(b"x")), ErrorKind::PermissionDenied);
+    assert_eq!(kind(File::open("/dir/file").unwrap().seek(SeekFrom::Current(-1))), ErrorKind::InvalidInput);
+
+    // 原来的FsError可以从io::Error中取回
+    let err: io::Error = super::read("/missing").unwrap_err();
+    let inner = err.get_ref().and_then(|inner| inner.downcast_ref::<FsError>());
+    assert_eq!(inner, Some(&FsError::NotFound(String::from("/missing"))));
+
+    // 宿主机的错误转换为FsError再转换回来，类型不变
+    for host_kind in [ErrorKind::PermissionDenied, ErrorKind::UnexpectedEof, ErrorKind::StorageFull] {
+        let err: FsError = FsError::from(io::Error::from(host_kind));
+        assert!(matches!(&err, FsError::Io(kind, _) if *kind == host_kind), "{:?}", err);
+        assert_eq!(io::Error::from(err).kind(), host_kind);
+    }
+}
@@ -0,0 +1,37 @@
+//! 使用Rust语言写的一个虚拟文件系统
+//!
+//! 用一个真实文件模拟磁盘，使用文件分配表（FAT）记录各个文件分别使用了哪些磁盘块。
+//!
+//! ```no_run
+//! use rust_file_system::DiskInfo;
+//!
+//! // 新建文件系统，创建目录和文件
+//! let mut disk = DiskInfo::new(None);
+//! disk.new_directory_to_disk("src").unwrap();
+//! disk.change_current_directory("src").unwrap();
+//! disk.create_file_with_data("main.rs", b"fn main() {}").unwrap();
+//! assert_eq!(disk.read_file_by_name("main.rs").unwrap(), b"fn main() {}");
+//!
+//! // 保存到镜像文件，之后可以再次打开
+//! disk.save_to_file("./file_system").unwrap();
+//! let disk = DiskInfo::load_from_file("./file_system").unwrap();
+//! for fcb in disk.current_directory().files() {
+//!     println!("{}\t{}\t{}", fcb.name(), fcb.file_type(), fcb.length());
+//! }
+//! ```
+
+pub mod disk_info;
+pub mod fs;
+
+pub use disk_info::allocation::{AllocationPolicy, FragmentationStats};
+pub use disk_info::block_device::{BlockDevice, FileDevice, MemoryDevice, MmapDevice};
+pub use disk_info::block_map::{BlockMap, BlockState, FileFragments};
+pub use disk_info::defrag::DefragReport;
+pub use disk_info::error::{FsError, FsResult};
+pub use disk_info::file_handle::{FileHandle, OpenFlags};
+pub use disk_info::fsck::{FsckProblem, FsckReport};
+pub use disk_info::image::Geometry;
+pub use disk_info::scrub::{BadBlock, ScrubReport};
+pub use disk_info::virtual_disk::{FatStatus, VirtualDisk, BLOCK_COUNT, BLOCK_SIZE};
+pub use disk_info::virtual_file::{DiskAccess, VirtualFile};
+pub use disk_info::{Directory, DiskInfo, Fcb, FileType};
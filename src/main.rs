@@ -1,13 +1,10 @@
-#![allow(dead_code)]
-
-mod disk_info;
-
-use std::str;
-use std::fs;
+use std::env;
+use std::process;
 use std::time::SystemTime;
 use std::io::{Write, stdin, stdout};
-use disk_info::*;
-use disk_info::virtual_disk::*;
+use ansi_rgb::Foreground;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rust_file_system::*;
 
 const FILE_NAME: &str = "./file_system";
 
@@ -16,48 +13,254 @@ const PROMPT: &str = "\
 \n\t                    rust_file_system\
 \n\t----------------------------------------------------------\
 \n\tCommands:\
-\n\t - cd <directory_name>: Change current directory.\
-\n\t - touch <filename>: Create a new file.\
-\n\t - ls : List all files and directory in current directory.\
-\n\t - cat <filename>: Show the file content.\
-\n\t - mkdir <directory name>: Create a new directory.\
-\n\t - cp <filename> <new_filename>: Copy a file.\
-\n\t - rename <raw_name> <new_name>: Rename a file.\
-\n\t - rm <filename>: Delete a file on disk.\
-\n\t - mv <filename> <path>: Move a file on disk.\
-\n\t - save : Save this virtual disk to file 'file_system'.\
-\n\t - diskinfo : Show some info about disk.\
-\n\t - exit : Exit the system.\
+\n\t - cd <path>: Change current directory.\
+\n\t - pwd : Show the path of current directory.\
+\n\t - touch <path>: Create a new file.\
+\n\t - ls [path]: List all files and directory in a directory.\
+\n\t - cat <path>: Show the file content.\
+\n\t - mkdir <path>: Create a new directory.\
+\n\t - cp [-r] <path> <new_path>: Copy a file, -r copies a directory recursively.\
+\n\t - rename <path> <new_path>: Rename (and move) a file or directory.\
+\n\t - rm [-r] <path>: Delete a file on disk, -r deletes a directory recursively.\
+\n\t - mv <path> <directory_path>: Move a file or directory on disk.\
+\n\t   Paths can be absolute (/a/b) or relative (a/b, ./a, ../a).\
+\n\t - save [generations]: Save this virtual disk to file 'file_system', keeping up to generations old images as 'file_system.bak1', 'file_system.bak2', ...\
+\n\t - diskinfo : Show disk usage and a fragmentation report.\
+\n\t - blockmap : Show which file owns each block as a colored grid.\
+\n\t - fsck [-r]: Check the file system for corrupted chains, leaked blocks and broken links, -r repairs what it can.\
+\n\t - scrub : Verify the checksum of every used block and report the files with corrupted blocks.\
+\n\t - defrag [max_blocks]: Move files into contiguous blocks, stopping after about max_blocks moved blocks.\
+\n\t - policy [first-fit|next-fit|best-fit|worst-fit|contiguous]: Show fragmentation statistics or change the allocation policy.\
+\n\t - exit : Exit the system, optionally saving it first.\
 \n\t - test create <file_name>: Create a random test file.\
 \n";
 
+const MKFS_USAGE: &str = "\
+Usage: rust_file_system mkfs [--block-size <bytes>] [--block-count <n> | --size <bytes>[K|M|G]] [--journal-blocks <n>] [path]\
+\n\tCreate an empty file system image at path (default './file_system').\
+\n\tThe block size must be a power of two between 512 bytes and 16 MiB (default 4096).\
+\n\t--size sets the size of the whole image; the block count is derived from it (default 1000 blocks).\
+\n\t--journal-blocks sets the size of the journal region, 0 disables the journal, otherwise at least 16 (default 1/32 of the blocks).";
+
+const FSCK_USAGE: &str = "\
+Usage: rust_file_system fsck [--repair] [path]\
+\n\tCheck the file system image at path (default './file_system') and report any problems.\
+\n\t--repair fixes what it can and writes the changes back to the image.\
+\n\tExits with status 1 if problems remain.";
+
+const SCRUB_USAGE: &str = "\
+Usage: rust_file_system scrub [path]\
+\n\tVerify the checksum of every used block in the file system image at path (default './file_system').\
+\n\tExits with status 1 if any block is corrupted.";
+
+// 交互界面使用的文件系统，块设备可能是镜像文件，也可能在内存中
+type ShellDisk = DiskInfo<Box<dyn BlockDevice>>;
+
+// 把文件系统库的日志按[INFO]、[DEBUG]的格式打印出来
+struct ShellLogger;
+
+impl Log for ShellLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("rust_file_system")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error | Level::Warn => print_error_info(),
+            Level::Info => print_info(),
+            Level::Debug | Level::Trace => print_debug_info(),
+        }
+        println!("{}", record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: ShellLogger = ShellLogger;
+
 fn main() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Debug);
+    }
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("mkfs") {
+        // 只创建镜像文件，不进入交互界面
+        match parse_mkfs_args(&args[1..]) {
+            Ok((geometry, path)) => match DiskInfo::create(&path, geometry) {
+                Ok(_) => {
+                    print_info();
+                    println!(
+                        "Created '{}': {} blocks of {} bytes, {} journal blocks, {} bytes in total",
+                        path, geometry.block_count, geometry.block_size, geometry.journal_blocks, geometry.image_len()
+                    );
+                }
+                Err(err) => {
+                    report_error::<()>(Err(err));
+                    process::exit(1);
+                }
+            },
+            Err(message) => {
+                print_error_info();
+                println!("{}\n{}", message, MKFS_USAGE);
+                process::exit(2);
+            }
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("fsck") {
+        // 只检查镜像文件，不进入交互界面
+        let mut repair: bool = false;
+        let mut path: Option<&str> = None;
+        for arg in &args[1..] {
+            match arg.as_str() {
+                "--repair" => repair = true,
+                _ if !arg.starts_with('-') && path.is_none() => path = Some(arg),
+                _ => {
+                    print_error_info();
+                    println!("unexpected argument {}\n{}", arg, FSCK_USAGE);
+                    process::exit(2);
+                }
+            }
+        }
+        let result: FsResult<FsckReport> =
+            DiskInfo::open(path.unwrap_or(FILE_NAME)).and_then(|mut disk| disk.fsck(repair));
+        match result {
+            Ok(report) => {
+                println!("{}", report);
+                if !report.is_clean() {
+                    process::exit(1);
+                }
+            }
+            Err(err) => {
+                report_error::<()>(Err(err));
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("scrub") {
+        // 只检查镜像文件中每一块的校验和，不进入交互界面
+        if args.len() > 2 || args.get(1).is_some_and(|arg| arg.starts_with('-')) {
+            print_error_info();
+            println!("unexpected argument {}\n{}", args[args.len() - 1], SCRUB_USAGE);
+            process::exit(2);
+        }
+        let path: &str = args.get(1).map(String::as_str).unwrap_or(FILE_NAME);
+        match DiskInfo::open(path).and_then(|mut disk| disk.scrub()) {
+            Ok(report) => {
+                println!("{}", report);
+                if !report.is_clean() {
+                    process::exit(1);
+                }
+            }
+            Err(err) => {
+                report_error::<()>(Err(err));
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
     // 是否从文件读取数据
-    let mut virtual_disk: DiskInfo = select_load_file_system(FILE_NAME);
-    command_loop(&mut virtual_disk);
+    let (mut virtual_disk, from_file) = select_load_file_system(FILE_NAME);
+    command_loop(&mut virtual_disk, from_file);
 }
 
-// 选择是否从文件加载虚拟文件系统
-fn select_load_file_system(filename: &str) -> DiskInfo {
+// 解析mkfs的参数，返回磁盘参数和镜像文件路径
+fn parse_mkfs_args(args: &[String]) -> Result<(Geometry, String), String> {
+    let default: Geometry = Geometry::default();
+    let mut block_size: usize = default.block_size;
+    let mut block_count: Option<usize> = None;
+    let mut total_size: Option<u64> = None;
+    let mut journal_blocks: Option<usize> = None;
+    let mut path: Option<String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--block-size" | "--block-count" | "--size" | "--journal-blocks" => {
+                let value: &String = args.next().ok_or(format!("missing value for {}", arg))?;
+                let size: u64 = parse_size(value).ok_or(format!("bad value for {}: {}", arg, value))?;
+                match arg.as_str() {
+                    "--block-size" => block_size = size as usize,
+                    "--block-count" => block_count = Some(size as usize),
+                    "--journal-blocks" => journal_blocks = Some(size as usize),
+                    _ => total_size = Some(size),
+                }
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    let geometry: FsResult<Geometry> = match (block_count, total_size) {
+        (Some(_), Some(_)) => return Err(String::from("--block-count and --size cannot be used together")),
+        (Some(block_count), None) => Geometry::new(block_size, block_count),
+        (None, Some(total_size)) => Geometry::with_total_size(block_size, total_size),
+        (None, None) => Geometry::new(block_size, default.block_count),
+    };
+    let geometry: FsResult<Geometry> = match journal_blocks {
+        Some(journal_blocks) => geometry.and_then(|geometry| geometry.with_journal_blocks(journal_blocks)),
+        None => geometry,
+    };
+    let geometry: Geometry = geometry.map_err(|err| err.to_string())?;
+    Ok((geometry, path.unwrap_or_else(|| String::from(FILE_NAME))))
+}
+
+// 解析字节数，可以带K、M、G后缀
+fn parse_size(value: &str) -> Option<u64> {
+    let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => value.split_at(index),
+        None => (value, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// 选择是否从文件加载虚拟文件系统，返回文件系统和是否直接在镜像文件上读写
+fn select_load_file_system(filename: &str) -> (ShellDisk, bool) {
     let mut buf_str: String = String::new();
     loop {
         print_info();
         print!("load file system from disk? [Y/N] ");
         stdout().flush().unwrap();
         stdin().read_line(&mut buf_str).unwrap();
-        let first_char: char = buf_str.as_str().trim().chars().next().unwrap();
+        let first_char: char = buf_str.as_str().trim().chars().next().unwrap_or(' ');
+        buf_str.clear();
 
         match first_char {
             'Y' | 'y' => {
                 print_info();
                 println!("load file system from disk\n");
-                let data: Vec<u8> = fs::read(filename).unwrap();
-                break bincode::deserialize(data.as_slice()).unwrap();
+                // 直接在镜像文件上读写，不需要把整个镜像读入内存，块大小以超级块中记录的为准
+                let device: FsResult<Box<dyn BlockDevice>> = Geometry::read_from_image(filename)
+                    .and_then(|geometry| FileDevice::open(filename, geometry.block_size))
+                    .map(|device| Box::new(device) as Box<dyn BlockDevice>);
+                match device.and_then(DiskInfo::open_device) {
+                    Ok(disk) => break (disk, true),
+                    Err(err) => {
+                        report_error::<()>(Err(err));
+                        continue;
+                    }
+                }
             }
             'N' | 'n' => {
                 print_info();
                 println!("new virtual file system\n");
-                break DiskInfo::new(None);
+                let geometry: Geometry = Geometry::default();
+                let device: MemoryDevice = MemoryDevice::new(geometry.block_size, geometry.device_block_count());
+                // 数据区在内存中，不会失败
+                break (DiskInfo::format_device(Box::new(device) as Box<dyn BlockDevice>, geometry).unwrap(), false);
             }
             _ => {
                 println!("\nIncorrect command.");
@@ -67,8 +270,56 @@ fn select_load_file_system(filename: &str) -> DiskInfo {
     }
 }
 
+// 保存虚拟文件系统：从镜像文件打开的只需要把修改写入镜像文件，新建的需要生成整个镜像文件
+// 生成整个镜像文件时原来的镜像最多保留generations代备份
+fn save_file_system(virtual_disk: &mut ShellDisk, filename: &str, from_file: bool, generations: usize) -> FsResult<()> {
+    if from_file {
+        if generations > 0 {
+            // 镜像文件一直在原地修改，没有"原来的镜像"可以保留
+            print_info();
+            println!("The file system is written to '{}' in place, no backups are kept.", filename);
+        }
+        virtual_disk.flush()
+    } else {
+        virtual_disk.save_to_file_with_backups(filename, generations)
+    }
+}
+
+// 退出前选择是否保存虚拟文件系统，返回是否可以退出
+fn select_save_file_system(virtual_disk: &mut ShellDisk, filename: &str, from_file: bool) -> bool {
+    let mut buf_str: String = String::new();
+    loop {
+        print_info();
+        print!("save file system to disk before exit? [Y/N] ");
+        stdout().flush().unwrap();
+        buf_str.clear();
+        stdin().read_line(&mut buf_str).unwrap();
+        let first_char: char = buf_str.as_str().trim().chars().next().unwrap_or(' ');
+
+        match first_char {
+            'Y' | 'y' => {
+                print_info();
+                println!("Saving virtual file system...");
+                match save_file_system(virtual_disk, filename, from_file, 0) {
+                    Ok(()) => break true,
+                    Err(err) => {
+                        // 保存失败时不退出，避免丢失数据
+                        report_error::<()>(Err(err));
+                        break false;
+                    }
+                }
+            }
+            'N' | 'n' => break true,
+            _ => {
+                println!("\nIncorrect command.");
+                continue;
+            }
+        };
+    }
+}
+
 // UI交互界面
-fn command_loop(virtual_disk: &mut DiskInfo) {
+fn command_loop(virtual_disk: &mut ShellDisk, from_file: bool) {
     // 提示
     println!("{}", PROMPT);
 
@@ -76,7 +327,7 @@ fn command_loop(virtual_disk: &mut DiskInfo) {
 
     loop {
         buf_str.clear();    // 清空buffer
-        print!(">  ");
+        print!("{} >  ", virtual_disk.current_path());
         stdout().flush().unwrap();
         stdin().read_line(&mut buf_str).unwrap();
         // 去除首尾空格
@@ -86,85 +337,204 @@ fn command_loop(virtual_disk: &mut DiskInfo) {
         if let Some(cl) = command_line.strip_prefix("touch ") {
             let file_name: &str = cl.trim();
             let data: String = format!("Generate file at {:?} .", SystemTime::now());
-            virtual_disk.create_file_with_data(file_name, data.as_bytes());
+            report_error(virtual_disk.create_file_with_data(file_name, data.as_bytes()));
         } else if command_line.starts_with("help") {
             // 显示菜单
             println!("{}", PROMPT);
-        } else if command_line.starts_with("save") {
-            // 保存系统
+        } else if let Some(command_line) = command_line.strip_prefix("save") {
+            // 保存系统，可以指定保留几代备份
+            let generations: usize = match command_line.trim() {
+                "" => 0,
+                value => match value.parse::<usize>() {
+                    Ok(generations) => generations,
+                    Err(_) => {
+                        println!("Parameter Error!");
+                        continue;
+                    }
+                },
+            };
             print_info();
             println!("Saving virtual file system...");
-            let data: Vec<u8> = bincode::serialize(&virtual_disk).unwrap();
-            fs::write(FILE_NAME, data.as_slice()).unwrap();
+            if let Err(err) = save_file_system(virtual_disk, FILE_NAME, from_file, generations) {
+                report_error::<()>(Err(err));
+                continue;
+            }
             print_info();
             println!("The virtual file system has been saved.\n");
+        } else if command_line == "pwd" {
+            // 显示当前目录的路径
+            println!("{}", virtual_disk.current_path());
         } else if command_line.starts_with("exit") {
             // 退出文件系统
-            print_info();
-            println!("Exiting file system...\n");
-            break;
-        } else if command_line.starts_with("ls") {
-            // 列出目录文件
-            println!("{}", virtual_disk.cur_directory);
+            if select_save_file_system(virtual_disk, FILE_NAME, from_file) {
+                print_info();
+                println!("Exiting file system...\n");
+                break;
+            }
+        } else if command_line == "ls" || command_line.starts_with("ls ") {
+            // 列出目录文件，未给出路径时列出当前目录
+            let path: &str = command_line[2..].trim();
+            if path.is_empty() {
+                println!("{}", virtual_disk.current_directory());
+            } else {
+                match virtual_disk.get_directory_by_path(path) {
+                    Ok(dir) => println!("{}", dir),
+                    Err(err) => report_error::<()>(Err(err)),
+                }
+            }
         } else if let Some(command_line) = command_line.strip_prefix("rm ") {
-            let file_name: &str = command_line.trim();
-            virtual_disk.delete_file_by_name(file_name)
-                .expect("[ERROR]\tFile not found, please enter the correct file name!");
+            // 删除，-r表示递归删除目录
+            match command_line.trim().strip_prefix("-r ") {
+                Some(path) => report_error(virtual_disk.delete_file_by_name_recursively(path.trim())),
+                None => report_error(virtual_disk.delete_file_by_name(command_line.trim())),
+            }
         } else if let Some(dir_name) = command_line.strip_prefix("cd ") {
             // 切换到当前目录的某个子目录
             print_info();
 
             println!("Change Current Directory to: {}", dir_name);
-            virtual_disk.change_current_directory(dir_name);
+            report_error(virtual_disk.change_current_directory(dir_name.trim()));
         } else if let Some(command_line) = command_line.strip_prefix("cat ") {
             // 查看文件内容
             let file_name: &str = command_line.trim();
-            let data: Vec<u8> = virtual_disk.read_file_by_name(file_name);
-
-            println!("{}", str::from_utf8(data.as_slice()).unwrap());
+            match virtual_disk.read_file_by_name(file_name) {
+                Ok(data) => println!("{}", String::from_utf8_lossy(data.as_slice())),
+                Err(err) => report_error::<()>(Err(err)),
+            }
         } else if let Some(command_line) = command_line.strip_prefix("cp ") {
             // 复制文件
-            let name: Vec<&str> = command_line.trim().split(" ").collect();
+            // 复制，-r表示递归复制目录
+            let mut name: Vec<&str> = command_line.split_whitespace().collect();
+            let recursive: bool = name.first() == Some(&"-r");
+            if recursive {
+                name.remove(0);
+            }
             if name.len() != 2 {
                 println!("Parameter Error!");
                 continue;
             }
-            virtual_disk.copy_file_by_name(name[0], name[1]);
+            if recursive {
+                report_error(virtual_disk.copy_file_by_name_recursively(name[0], name[1]));
+            } else {
+                report_error(virtual_disk.copy_file_by_name(name[0], name[1]));
+            }
         } else if command_line.starts_with("diskinfo") {
             // 统计磁盘使用情况
             let (total_size, already_used, unused) = virtual_disk.get_disk_info();
-            println!("total size: {} Bytes\nalready use: {} Bytes\navailable: {} Bytes",
+            let block_size: usize = virtual_disk.virtual_disk().block_size();
+            println!("block size: {} Bytes\ntotal size: {} Bytes\nalready use: {} Bytes\navailable: {} Bytes",
+                    block_size,
                     total_size,
-                    BLOCK_SIZE * already_used,
-                    BLOCK_SIZE * unused 
+                    block_size * already_used,
+                    block_size * unused
             );
+            match virtual_disk.block_map() {
+                Ok(block_map) => println!("{}", block_map.summary()),
+                Err(err) => report_error::<()>(Err(err)),
+            }
+        } else if let Some(command_line) = command_line.strip_prefix("defrag") {
+            // 碎片整理，可以限制移动的块数，之后再次执行会继续整理
+            let max_blocks: Option<usize> = match command_line.trim() {
+                "" => None,
+                value => match value.parse::<usize>() {
+                    Ok(max_blocks) => Some(max_blocks),
+                    Err(_) => {
+                        println!("Parameter Error!");
+                        continue;
+                    }
+                },
+            };
+            let result: FsResult<DefragReport> = virtual_disk
+                .defragment(|report| max_blocks.is_none_or(|max_blocks| report.blocks_moved < max_blocks));
+            match result {
+                Ok(report) => println!("{}", report),
+                Err(err) => report_error::<()>(Err(err)),
+            }
+        } else if let Some(command_line) = command_line.strip_prefix("fsck") {
+            // 检查文件系统，-r表示修复发现的问题
+            let repair: bool = match command_line.trim() {
+                "" => false,
+                "-r" => true,
+                _ => {
+                    println!("Parameter Error!");
+                    continue;
+                }
+            };
+            match virtual_disk.fsck(repair) {
+                Ok(report) => println!("{}", report),
+                Err(err) => report_error::<()>(Err(err)),
+            }
+        } else if command_line == "scrub" {
+            // 检查所有已使用的块的校验和
+            match virtual_disk.scrub() {
+                Ok(report) => println!("{}", report),
+                Err(err) => report_error::<()>(Err(err)),
+            }
+        } else if command_line == "blockmap" {
+            // 显示磁盘块分布图
+            match virtual_disk.block_map() {
+                Ok(block_map) => println!("{}", block_map),
+                Err(err) => report_error::<()>(Err(err)),
+            }
+        } else if let Some(command_line) = command_line.strip_prefix("policy") {
+            // 查看碎片统计或修改分配策略
+            let name: &str = command_line.trim();
+            if !name.is_empty() {
+                match name.parse::<AllocationPolicy>() {
+                    Ok(policy) => virtual_disk.set_allocation_policy(policy),
+                    Err(message) => {
+                        print_error_info();
+                        println!("{}", message);
+                        continue;
+                    }
+                }
+            }
+            println!("{}", virtual_disk.fragmentation_stats());
         } else if let Some(command_line) = command_line.strip_prefix("mkdir ") {
             // 创建新目录
             let dir_name = command_line.trim();
-            virtual_disk.new_directory_to_disk(dir_name).unwrap();
+            report_error(virtual_disk.new_directory_to_disk(dir_name));
         }  else if let Some(command_line) = command_line.strip_prefix("mv ") {
             // 移动文件
-            let name: Vec<&str> = command_line.trim().split(" ").collect();
+            let name: Vec<&str> = command_line.split_whitespace().collect();
             if name.len() != 2 {
                 println!("Parameter Error!");
                 continue;
             }
-            if name[1].contains("/") {
-                // 移动，path暂时只支持相对路径
-                virtual_disk.movie_file_by_name(name[0], name[1]);
-            }
+            // 移动，path可以是绝对路径或相对路径
+            report_error(virtual_disk.movie_file_by_name(name[0], name[1]));
         } else if let Some(command_line) = command_line.strip_prefix("rename ") {
             // 重命名
-            let name: Vec<&str> = command_line.trim().split(" ").collect();
+            let name: Vec<&str> = command_line.split_whitespace().collect();
             if name.len() != 2 {
                 println!("Parameter Error!");
                 continue;
             }
-            virtual_disk.rename_file_by_name(name[0], name[1]);
-        } 
+            report_error(virtual_disk.rename_file_by_name(name[0], name[1]));
+        }
         else {
             // 不支持的命令
             println!("Unsupported command");
         }
     }
 }
+
+// 打印命令执行出错的信息，出错后继续运行
+fn report_error<T>(result: FsResult<T>) {
+    if let Err(err) = result {
+        print_error_info();
+        println!("{}", err);
+    }
+}
+
+fn print_debug_info() {
+    print!("{}", "[DEBUG]\t".fg(ansi_rgb::magenta()));
+}
+
+fn print_info() {
+    print!("{}", "[INFO]\t".fg(ansi_rgb::cyan_blue()));
+}
+
+fn print_error_info() {
+    print!("{}", "[ERROR]\t".fg(ansi_rgb::red()));
+}